@@ -0,0 +1,156 @@
+use std::error::Error;
+use std::fmt;
+use std::path::Path;
+use syntect::highlighting::{Theme, ThemeSet};
+use syntect::html::{
+    css_for_theme_with_class_style, highlighted_html_for_string, line_tokens_to_classed_spans,
+    ClassStyle,
+};
+use syntect::parsing::{ParseState, ScopeStack, SyntaxSet};
+use syntect::util::LinesWithEndings;
+
+pub const DEFAULT_THEME: &str = "base16-ocean.dark";
+const CLASS_STYLE: ClassStyle = ClassStyle::SpacedPrefixed { prefix: "hl-" };
+
+#[derive(Debug)]
+pub enum HighlightError {
+    UnknownTheme(String, Vec<String>),
+    InvalidTheme(String, String),
+    InvalidSyntax(String, String),
+}
+
+impl fmt::Display for HighlightError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HighlightError::UnknownTheme(name, available) => write!(
+                f,
+                "unknown theme `{}`, expected a .tmTheme file or one of: {}",
+                name,
+                available.join(", ")
+            ),
+            HighlightError::InvalidTheme(path, reason) => {
+                write!(f, "failed to load theme `{}`: {}", path, reason)
+            }
+            HighlightError::InvalidSyntax(path, reason) => {
+                write!(f, "failed to load syntaxes from `{}`: {}", path, reason)
+            }
+        }
+    }
+}
+
+impl Error for HighlightError {}
+
+/// Syntax definitions and theme used to highlight code blocks.
+///
+/// Loading these is expensive, so a single instance is shared by all code
+/// blocks that are rendered with the same `Renderer`.
+pub struct Highlighter {
+    syntax_set: SyntaxSet,
+    theme: Theme,
+    classes: bool,
+}
+
+impl Highlighter {
+    pub fn new() -> Self {
+        Highlighter::with_theme(DEFAULT_THEME).unwrap()
+    }
+
+    /// Creates a highlighter using one of the themes built into syntect, or
+    /// the `.tmTheme` file at `theme` if there is no built-in theme with
+    /// that name.
+    pub fn with_theme(theme: &str) -> Result<Self, HighlightError> {
+        let mut theme_set = ThemeSet::load_defaults();
+
+        let theme = match theme_set.themes.remove(theme) {
+            Some(x) => x,
+            None if Path::new(theme).is_file() => ThemeSet::get_theme(theme)
+                .map_err(|e| HighlightError::InvalidTheme(String::from(theme), e.to_string()))?,
+            None => {
+                return Err(HighlightError::UnknownTheme(
+                    String::from(theme),
+                    Highlighter::theme_names(),
+                ))
+            }
+        };
+
+        Ok(Highlighter {
+            syntax_set: SyntaxSet::load_defaults_newlines(),
+            theme,
+            classes: false,
+        })
+    }
+
+    /// Names of the themes that can be passed to `with_theme`.
+    pub fn theme_names() -> Vec<String> {
+        ThemeSet::load_defaults().themes.keys().cloned().collect()
+    }
+
+    /// Adds the `.sublime-syntax` definitions found in `folder` to the
+    /// languages that can be highlighted.
+    pub fn add_syntaxes(mut self, folder: &Path) -> Result<Self, HighlightError> {
+        let mut builder = self.syntax_set.into_builder();
+        builder.add_from_folder(folder, true).map_err(|e| {
+            HighlightError::InvalidSyntax(folder.display().to_string(), e.to_string())
+        })?;
+        self.syntax_set = builder.build();
+
+        Ok(self)
+    }
+
+    /// Emit `<span class="hl-...">` elements styled by `stylesheet` instead
+    /// of inline styles.
+    pub fn classes(mut self, classes: bool) -> Self {
+        self.classes = classes;
+        self
+    }
+
+    /// CSS for the classes emitted in class mode, using the colors of the
+    /// selected theme.
+    pub fn stylesheet(&self) -> String {
+        css_for_theme_with_class_style(&self.theme, CLASS_STYLE)
+    }
+
+    /// Highlights `code` as `lang`, returns `None` if the language is unknown.
+    /// An empty `lang` highlights the code as plain text.
+    pub fn highlight(&self, lang: &str, code: &str) -> Option<String> {
+        let syntax = if lang.trim().is_empty() {
+            self.syntax_set.find_syntax_plain_text()
+        } else {
+            self.syntax_set.find_syntax_by_token(&lang.to_lowercase())?
+        };
+
+        if !self.classes {
+            return Some(highlighted_html_for_string(
+                code,
+                &self.syntax_set,
+                syntax,
+                &self.theme,
+            ));
+        }
+
+        let mut state = ParseState::new(syntax);
+        let mut scopes = ScopeStack::new();
+        let mut open_spans = 0;
+        let mut html = String::from("<pre class=\"hl-code\">");
+
+        for line in LinesWithEndings::from(code) {
+            let ops = state.parse_line(line, &self.syntax_set);
+            let (line_html, delta) =
+                line_tokens_to_classed_spans(line, &ops, CLASS_STYLE, &mut scopes);
+            html.push_str(&line_html);
+            open_spans += delta;
+        }
+        for _ in 0..open_spans {
+            html.push_str("</span>");
+        }
+        html.push_str("</pre>");
+
+        Some(html)
+    }
+}
+
+impl Default for Highlighter {
+    fn default() -> Self {
+        Highlighter::new()
+    }
+}
@@ -1,2 +1,4 @@
 pub use markdown::*;
+pub mod highlight;
 pub mod markdown;
+pub mod math;
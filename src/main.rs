@@ -2,24 +2,78 @@ use clap::{App, Arg};
 use notify::{watcher, RecursiveMode, Watcher};
 use std::fs;
 use std::path::Path;
+use std::process;
 use std::time::Duration;
 use std::{thread, time};
 
 #[cfg(feature = "pdf")]
 use wkhtmltopdf::*;
 
-mod markdown;
-use markdown::*;
+use rabbit::highlight::{Highlighter, DEFAULT_THEME};
+use rabbit::math::{MathCache, MathOutput};
+use rabbit::*;
 
-fn compile(
-    path: &str,
-    output: Option<&str>,
-    header: &str,
-    header_fast: &str,
-    footer: &str,
+struct Options<'a> {
+    output: Option<&'a str>,
+    header: String,
+    header_fast: String,
+    footer: String,
     pdf: bool,
-) {
+    safe: bool,
+    strict: bool,
+    theme: Option<&'a str>,
+    highlight_css: Option<&'a str>,
+    syntaxes: Option<&'a str>,
+    math: Option<&'a str>,
+    math_cache: MathCache,
+}
+
+impl Options<'_> {
+    fn renderer<'p>(&self, base_path: &'p Path) -> Result<Renderer<'p>, String> {
+        let mut renderer = Renderer::new(base_path, !self.pdf)
+            .safe(self.safe)
+            .strict(self.strict)
+            .with_math_cache(self.math_cache.clone());
+
+        match self.math {
+            Some("mathjax") => renderer = renderer.math(MathOutput::MathJax),
+            Some("svg") => renderer = renderer.math(MathOutput::Svg),
+            Some("mathml") => renderer = renderer.math(MathOutput::MathMl),
+            _ => {}
+        }
+
+        if self.theme.is_some() || self.highlight_css.is_some() || self.syntaxes.is_some() {
+            let mut highlighter = Highlighter::with_theme(self.theme.unwrap_or(DEFAULT_THEME))
+                .map_err(|e| e.to_string())?
+                .classes(self.highlight_css.is_some());
+
+            if let Some(x) = self.syntaxes {
+                highlighter = highlighter
+                    .add_syntaxes(Path::new(x))
+                    .map_err(|e| e.to_string())?;
+            }
+
+            if let Some(x) = self.highlight_css {
+                fs::write(x, highlighter.stylesheet())
+                    .map_err(|e| format!("Failed to write stylesheet {}: {}", x, e))?;
+            }
+
+            renderer = renderer.with_highlighter(highlighter);
+        }
+
+        Ok(renderer)
+    }
+}
+
+fn print_warnings(path: &Path, renderer: &Renderer) {
+    for warning in renderer.take_warnings() {
+        eprintln!("warning: {}:{}", path.display(), warning);
+    }
+}
+
+fn compile(path: &str, options: &Options) -> Result<(), String> {
     let path = Path::new(path);
+    let output = options.output;
 
     println!("{:?}", path.file_name().unwrap());
 
@@ -28,11 +82,13 @@ fn compile(
     }
 
     let html = if path.is_dir() {
-        let mut parsed = String::new();
-
+        let renderer = options.renderer(path)?;
         let mut paths: Vec<_> = fs::read_dir(path).unwrap().map(|r| r.unwrap()).collect();
         paths.sort_by_key(|dir| dir.path());
 
+        // Parse every file before rendering, so references can point to
+        // labels in later files. Footnotes stay scoped to their own file.
+        let mut documents = vec![];
         for entry in paths {
             let entry_path = entry.path();
 
@@ -42,29 +98,49 @@ fn compile(
 
             if entry_path.extension().unwrap() == "md" {
                 println!("\t{:?}", entry_path.file_name().unwrap());
-                let markdown = fs::read_to_string(entry_path).unwrap();
+                let markdown = fs::read_to_string(&entry_path).unwrap();
                 let mut parser = Parser::new(&markdown);
-                parsed += &parser.get_html(path, !pdf);
+                let nodes = parser
+                    .by_ref()
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|e| format!("{}:{}", entry_path.display(), e))?;
+                let footnotes = parser.take_footnotes();
+                renderer.add_document_labels(&nodes, &footnotes);
+                print_warnings(&entry_path, &renderer);
+                documents.push((entry_path, nodes, footnotes));
             }
         }
 
+        let mut parsed = String::new();
+        for (entry_path, nodes, footnotes) in &documents {
+            parsed += &renderer
+                .render_document(nodes, footnotes)
+                .map_err(|e| format!("{}:{}", entry_path.display(), e))?;
+            print_warnings(entry_path, &renderer);
+        }
+
         parsed
     } else {
         let markdown = fs::read_to_string(path).unwrap();
+        let renderer = options.renderer(path.parent().unwrap())?;
         let mut parser = Parser::new(&markdown);
-        parser.get_html(path.parent().unwrap(), !pdf)
+        let html = parser
+            .get_html(&renderer)
+            .map_err(|e| format!("{}:{}", path.display(), e))?;
+        print_warnings(path, &renderer);
+        html
     };
 
-    let mut result = if pdf {
-        String::from(header)
+    let mut result = if options.pdf {
+        options.header.clone()
     } else {
-        String::from(header_fast)
+        options.header_fast.clone()
     };
 
     result.push_str(&html);
-    result.push_str(&footer);
+    result.push_str(&options.footer);
 
-    if pdf {
+    if options.pdf {
         #[cfg(feature = "pdf")]
         {
             let mut pdf_app = PdfApplication::new().expect("Failed to init PDF application");
@@ -93,6 +169,8 @@ fn compile(
             None => fs::write("output.html", result).unwrap(),
         }
     }
+
+    Ok(())
 }
 
 fn main() {
@@ -107,6 +185,49 @@ fn main() {
         .arg(Arg::with_name("header_fast").takes_value(true))
         .arg(Arg::with_name("footer").short("f").takes_value(true))
         .arg(Arg::with_name("watcher").short("w").takes_value(false))
+        .arg(
+            Arg::with_name("safe")
+                .short("s")
+                .long("safe")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("strict")
+                .long("strict")
+                .takes_value(false)
+                .help("Fail instead of warning when math cannot be rendered"),
+        )
+        .arg(
+            Arg::with_name("theme")
+                .long("theme")
+                .takes_value(true)
+                .help("Built-in highlighting theme or path to a .tmTheme file"),
+        )
+        .arg(
+            Arg::with_name("highlight_css")
+                .long("highlight-css")
+                .takes_value(true)
+                .help("Highlight code with CSS classes and write their stylesheet to this file"),
+        )
+        .arg(
+            Arg::with_name("syntaxes")
+                .long("syntaxes")
+                .takes_value(true)
+                .help("Folder with extra .sublime-syntax definitions for code blocks"),
+        )
+        .arg(
+            Arg::with_name("math")
+                .long("math")
+                .takes_value(true)
+                .possible_values(&["mathjax", "mathml", "svg"])
+                .help("How math is rendered, defaults to mathjax for html and mathml for pdf"),
+        )
+        .arg(
+            Arg::with_name("cache_dir")
+                .long("cache-dir")
+                .takes_value(true)
+                .help("Folder to keep rendered math in between runs"),
+        )
         .get_matches();
 
     let header: String = match matches.value_of("header") {
@@ -122,17 +243,32 @@ fn main() {
         None => String::from(include_str!("footer.html")),
     };
 
+    let math_cache = match matches.value_of("cache_dir") {
+        Some(x) => MathCache::with_dir(x).expect("Failed to create cache directory"),
+        None => MathCache::new(),
+    };
+
     let input_file = matches.value_of("input").unwrap();
-    let output_file = matches.value_of("output");
+    let options = Options {
+        output: matches.value_of("output"),
+        header,
+        header_fast,
+        footer,
+        pdf: matches.is_present("pdf"),
+        safe: matches.is_present("safe"),
+        strict: matches.is_present("strict"),
+        theme: matches.value_of("theme"),
+        highlight_css: matches.value_of("highlight_css"),
+        syntaxes: matches.value_of("syntaxes"),
+        math: matches.value_of("math"),
+        math_cache,
+    };
 
-    compile(
-        input_file,
-        output_file,
-        &header,
-        &header_fast,
-        &footer,
-        matches.is_present("pdf"),
-    );
+    let result = compile(input_file, &options);
+
+    if let Err(err) = &result {
+        eprintln!("error: {}", err);
+    }
 
     if matches.is_present("watcher") {
         let (tx, rx) = std::sync::mpsc::channel();
@@ -147,18 +283,17 @@ fn main() {
             match rx.recv() {
                 Ok(_) => {
                     thread::sleep(time::Duration::from_millis(100));
-                    compile(
-                        input_file,
-                        output_file,
-                        &header,
-                        &header_fast,
-                        &footer,
-                        matches.is_present("pdf"),
-                    );
-                    println!("Recompiled {}", input_file);
+                    match compile(input_file, &options) {
+                        Ok(()) => println!("Recompiled {}", input_file),
+                        Err(err) => eprintln!("error: {}", err),
+                    }
                 }
                 Err(err) => println!("watch error: {:?}", err),
             }
         }
     }
+
+    if result.is_err() {
+        process::exit(1);
+    }
 }
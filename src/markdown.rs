@@ -1,376 +1,2578 @@
+use crate::highlight::Highlighter;
+use crate::math::{tex_to_mathml, tex_to_svg, MathCache, MathOutput};
+use std::cell::{OnceCell, RefCell};
+use std::collections::HashMap;
+use std::error::Error;
 use std::ffi::OsStr;
-use std::fs;
+use std::fmt;
 use std::fs::File;
 use std::io::Read;
 use std::path::Path;
-use std::process::Command;
-use syntect::highlighting::ThemeSet;
-use syntect::html::highlighted_html_for_string;
-use syntect::parsing::SyntaxSet;
+use std::rc::Rc;
 use unicode_segmentation::UnicodeSegmentation;
 
+/// Location of a node in the source document.
+///
+/// `start` and `end` are byte offsets into the input, `line` and `column`
+/// point at the first character of the node and are 1-based.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
 #[derive(Debug)]
 pub enum MarkdownNode {
-    Header(String, usize),
-    Paragraph(Vec<ParagraphItem>, bool),
-    List(Vec<MarkdownNode>),
-    Math(String),
-    Code(String, String),
-    Table(Vec<MarkdownNode>, Vec<MarkdownNode>),
-    PageBreak(),
+    Header(String, usize, Attributes, Span),
+    Paragraph(Vec<ParagraphItem>, bool, Span),
+    List(Vec<ListItem>, Span),
+    BlockQuote(Vec<MarkdownNode>, Span),
+    OrderedList(Vec<ListItem>, usize, Span),
+    Math(String, Option<String>, Span),
+    Code(String, String, Attributes, Option<Caption>, Span),
+    Table(
+        Vec<MarkdownNode>,
+        Vec<Vec<MarkdownNode>>,
+        Vec<Alignment>,
+        Option<Caption>,
+        Span,
+    ),
+    Figure(ParagraphItem, Option<String>, Span),
+    PageBreak(Span),
 }
 
 #[derive(Debug)]
 pub enum ParagraphItem {
-    Text(String),
-    Italic(String),
-    Bold(String),
-    Url(String, String),
-    InlineMath(String),
-    Image(String, String),
-    InlineCode(String),
+    Text(String, Span),
+    Italic(Vec<ParagraphItem>, Span),
+    Bold(Vec<ParagraphItem>, Span),
+    Strikethrough(Vec<ParagraphItem>, Span),
+    Highlight(Vec<ParagraphItem>, Span),
+    Superscript(Vec<ParagraphItem>, Span),
+    Subscript(Vec<ParagraphItem>, Span),
+    Url(Vec<ParagraphItem>, String, Attributes, Span),
+    InlineMath(String, Span),
+    Image(String, String, Attributes, Span),
+    InlineCode(String, Span),
+    Html(String, Span),
+    Reference(String, Span),
+    FootnoteReference(String, Span),
 }
 
-pub struct Parser<'a> {
-    data: Vec<&'a str>,
-    index: usize,
+/// Alignment of a table column, set by colons in the delimiter row like
+/// `| :--- | :---: | ---: |`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Alignment {
+    None,
+    Left,
+    Center,
+    Right,
 }
 
-pub trait ToHtml {
-    fn to_html(&self, base_path: &Path, fast: bool) -> String;
+impl Alignment {
+    fn style(self) -> &'static str {
+        match self {
+            Alignment::None => "",
+            Alignment::Left => " style=\"text-align:left\"",
+            Alignment::Center => " style=\"text-align:center\"",
+            Alignment::Right => " style=\"text-align:right\"",
+        }
+    }
 }
 
-impl ToHtml for ParagraphItem {
-    fn to_html(&self, base_path: &Path, fast: bool) -> String {
-        match self {
-            ParagraphItem::Text(text) => String::from(text),
-            ParagraphItem::Italic(text) => format!("<em>{}</em>", text),
-            ParagraphItem::Bold(text) => format!("<b>{}</b>", text),
-            ParagraphItem::Url(name, url) => format!("<a href=\"{}\">{}</a>", url, name),
-            ParagraphItem::InlineMath(math) => {
-                if fast {
-                    format!("${}$", math)
-                } else {
-                    tex_to_svg(math, true)
+/// The caption of a table or the title of a code listing, written on the
+/// line after it like `Table: Results {#tbl:results}`.
+#[derive(Debug)]
+pub struct Caption {
+    pub text: String,
+    pub label: Option<String>,
+    pub span: Span,
+}
+
+/// Attributes like `{#id .class width=50%}` written after links, images,
+/// headers and code blocks. The title of a link or image is stored as a
+/// `title` value.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Attributes {
+    pub id: Option<String>,
+    pub classes: Vec<String>,
+    pub values: Vec<(String, String)>,
+}
+
+impl Attributes {
+    pub fn is_empty(&self) -> bool {
+        self.id.is_none() && self.classes.is_empty() && self.values.is_empty()
+    }
+
+    pub fn title(&self) -> Option<&str> {
+        self.values
+            .iter()
+            .find(|(key, _)| key == "title")
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Parses the text between the braces of an attribute list, returns
+    /// `None` if it is not a valid one.
+    fn parse(text: &str) -> Option<Attributes> {
+        let mut attributes = Attributes::default();
+        let mut chars = text.chars().peekable();
+
+        loop {
+            while chars.next_if(|c| c.is_whitespace()).is_some() {}
+
+            let first = match chars.peek() {
+                Some(c) => *c,
+                None => break,
+            };
+
+            if first == '#' || first == '.' {
+                chars.next();
+            }
+
+            let mut name = String::new();
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace() && *c != '=') {
+                name.push(c);
+            }
+            if name.is_empty() {
+                return None;
+            }
+
+            match first {
+                '#' => attributes.id = Some(name),
+                '.' => attributes.classes.push(name),
+                _ => {
+                    let valid_key = name
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == ':');
+                    if !valid_key || chars.next() != Some('=') {
+                        return None;
+                    }
+
+                    let mut value = String::new();
+                    if chars.next_if_eq(&'"').is_some() {
+                        loop {
+                            match chars.next()? {
+                                '"' => break,
+                                '\\' if chars.peek() == Some(&'"') => value.push(chars.next()?),
+                                c => value.push(c),
+                            }
+                        }
+                    } else {
+                        while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                            value.push(c);
+                        }
+                    }
+
+                    attributes.values.push((name, value));
                 }
             }
-            ParagraphItem::Image(url, alt_text) => {
-                if fast {
-                    format!("<img src=\"{}\" alt=\"{}\">", url, alt_text)
-                } else {
-                    if url.contains("www.") || url.contains("http://") || url.contains("https://"){
-                        return format!("<img src=\"{}\" alt=\"{}\">", url, alt_text);
+        }
+
+        Some(attributes)
+    }
+
+    /// Converts the attributes to HTML, starting with a space. Safe mode only
+    /// keeps values that can't run scripts, load urls or restyle the page.
+    fn to_html(&self, renderer: &Renderer) -> String {
+        let mut result = String::default();
+
+        if let Some(id) = &self.id {
+            result.push_str(&format!(" id=\"{}\"", escape_html(id)));
+        }
+        if !self.classes.is_empty() {
+            result.push_str(&format!(
+                " class=\"{}\"",
+                escape_html(&self.classes.join(" "))
+            ));
+        }
+        for (key, value) in &self.values {
+            if renderer.safe && !is_safe_attribute(key) {
+                continue;
+            }
+            result.push_str(&format!(" {}=\"{}\"", key, escape_html(value)));
+        }
+
+        result
+    }
+
+    /// Adds the attributes to the opening `tag` in `html`, merging the
+    /// classes with the ones that are already there.
+    fn add_to(&self, html: &str, tag: &str, renderer: &Renderer) -> String {
+        let start = match html.find(&format!("<{}", tag)) {
+            Some(x) => x + tag.len() + 1,
+            None => return String::from(html),
+        };
+        let end = html[start..].find('>').map_or(html.len(), |x| start + x);
+
+        let mut attributes = self.clone();
+        let mut opening = String::from(&html[start..end]);
+        if let Some(class) = opening.find(" class=\"") {
+            if !attributes.classes.is_empty() {
+                let classes = escape_html(&attributes.classes.join(" "));
+                opening.insert_str(class + 8, &format!("{} ", classes));
+                attributes.classes.clear();
+            }
+        }
+
+        format!(
+            "{}{}{}{}",
+            &html[..start],
+            attributes.to_html(renderer),
+            opening,
+            &html[end..]
+        )
+    }
+}
+
+fn is_safe_attribute(key: &str) -> bool {
+    let key = key.to_lowercase();
+    matches!(key.as_str(), "width" | "height" | "title" | "lang" | "dir")
+        || key.starts_with("data-")
+}
+
+/// Finds a TeX command that lets math add links, styles or raw HTML to the
+/// output when rendered by MathJax, which safe mode doesn't allow.
+fn unsafe_tex_command(math: &str) -> Option<String> {
+    let mut chars = math.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            continue;
+        }
+
+        let name: String = chars
+            .clone()
+            .take_while(|c| c.is_ascii_alphabetic())
+            .collect();
+        if matches!(
+            name.as_str(),
+            "href" | "url" | "class" | "style" | "cssId" | "require"
+        ) || name.starts_with("html")
+        {
+            return Some(name);
+        }
+
+        // Skip the character after the backslash, so `\\href` is a line break
+        // followed by text
+        chars.next();
+    }
+
+    None
+}
+
+/// A single entry of a list, which can hold any number of blocks including
+/// nested lists.
+#[derive(Debug)]
+pub struct ListItem {
+    pub children: Vec<MarkdownNode>,
+    /// Whether the task of a `- [x]` item is done, `None` for other items.
+    pub checked: Option<bool>,
+    pub span: Span,
+}
+
+/// A footnote definition like `[^1]: Some text`, the parser collects these
+/// separately from the other nodes.
+#[derive(Debug)]
+pub struct Footnote {
+    pub label: String,
+    pub children: Vec<MarkdownNode>,
+    pub span: Span,
+}
+
+/// The footnotes of the document being rendered, numbered in the order
+/// they are first referenced.
+#[derive(Default)]
+struct FootnoteState {
+    defined: Vec<String>,
+    /// Labels with the number of references to them.
+    used: Vec<(String, usize)>,
+    /// Number of documents rendered, which keeps ids unique in a folder.
+    documents: usize,
+}
+
+impl FootnoteState {
+    fn prefix(&self) -> String {
+        match self.documents {
+            0 | 1 => String::from("fn"),
+            x => format!("fn{}", x),
+        }
+    }
+}
+
+/// A task list item like `- [ ] Write tests` found by `tasks`.
+#[derive(Debug)]
+pub struct Task<'a> {
+    pub item: &'a ListItem,
+    pub checked: bool,
+    pub line: usize,
+}
+
+/// Returns every task list item in `nodes`, including those in nested lists
+/// and block quotes, in document order.
+pub fn tasks(nodes: &[MarkdownNode]) -> Vec<Task<'_>> {
+    let mut result = vec![];
+
+    for node in nodes {
+        match node {
+            MarkdownNode::List(items, _) | MarkdownNode::OrderedList(items, _, _) => {
+                for item in items {
+                    if let Some(checked) = item.checked {
+                        result.push(Task {
+                            item,
+                            checked,
+                            line: item.span.line,
+                        });
                     }
+                    result.extend(tasks(&item.children));
+                }
+            }
+            MarkdownNode::BlockQuote(children, _) => result.extend(tasks(children)),
+            _ => {}
+        }
+    }
 
-                    let mut f = File::open(base_path.join(url)).expect("no file found");
-                    let metadata =
-                        fs::metadata(base_path.join(url)).expect("unable to read metadata");
-                    let mut buffer = vec![0; metadata.len() as usize];
-                    f.read_exact(&mut buffer).expect("buffer overflow");
-                    let image_data = base64::encode(buffer);
+    result
+}
 
-                    let extension = Path::new(url)
-                        .extension()
-                        .and_then(OsStr::to_str)
-                        .expect("Failed to get file extension");
+fn contains_link(items: &[ParagraphItem]) -> bool {
+    items.iter().any(|item| match item {
+        ParagraphItem::Url(..) => true,
+        ParagraphItem::Italic(children, _)
+        | ParagraphItem::Bold(children, _)
+        | ParagraphItem::Strikethrough(children, _)
+        | ParagraphItem::Highlight(children, _)
+        | ParagraphItem::Superscript(children, _)
+        | ParagraphItem::Subscript(children, _) => contains_link(children),
+        _ => false,
+    })
+}
 
-                    format!(
-                        "<img src=\"data:image/{};base64,{}\" alt=\"{}\">",
-                        extension, image_data, alt_text
-                    )
+/// Collects the labels of the footnote references in `nodes` in the order
+/// they are rendered.
+fn footnote_references<'a>(nodes: &'a [MarkdownNode], result: &mut Vec<&'a str>) {
+    for node in nodes {
+        match node {
+            MarkdownNode::Paragraph(items, _, _) => item_footnote_references(items, result),
+            MarkdownNode::List(items, _) | MarkdownNode::OrderedList(items, _, _) => {
+                for item in items {
+                    footnote_references(&item.children, result);
+                }
+            }
+            MarkdownNode::BlockQuote(children, _) => footnote_references(children, result),
+            MarkdownNode::Table(headers, rows, _, _, _) => {
+                footnote_references(headers, result);
+                for row in rows {
+                    footnote_references(row, result);
                 }
             }
-            ParagraphItem::InlineCode(code) => format!("<code>{}</code>", code),
+            _ => {}
+        }
+    }
+}
+
+fn item_footnote_references<'a>(items: &'a [ParagraphItem], result: &mut Vec<&'a str>) {
+    for item in items {
+        match item {
+            ParagraphItem::FootnoteReference(label, _) => result.push(label),
+            ParagraphItem::Italic(children, _)
+            | ParagraphItem::Bold(children, _)
+            | ParagraphItem::Strikethrough(children, _)
+            | ParagraphItem::Highlight(children, _)
+            | ParagraphItem::Superscript(children, _)
+            | ParagraphItem::Subscript(children, _)
+            | ParagraphItem::Url(children, _, _, _) => item_footnote_references(children, result),
+            _ => {}
         }
     }
 }
 
-fn tex_to_svg(input: &str, inline: bool) -> String {
-    let mut command = Command::new("tex2svg");
-    command.arg(input);
+/// The kind of element a label like `{#eq:energy}` is attached to, each
+/// kind is numbered separately.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LabelKind {
+    Equation,
+    Figure,
+    Table,
+    Listing,
+}
 
-    if inline {
-        command.arg("--inline");
+impl LabelKind {
+    /// The text a reference to the element with `number` is replaced with.
+    pub fn reference_text(self, number: usize) -> String {
+        match self {
+            LabelKind::Equation => format!("Eq. ({})", number),
+            LabelKind::Figure => format!("Figure {}", number),
+            LabelKind::Table => format!("Table {}", number),
+            LabelKind::Listing => format!("Listing {}", number),
+        }
     }
 
-    match command.output() {
-        Ok(x) => String::from_utf8(x.stdout).unwrap(),
-        Err(_) => {
-            eprintln!("Failed to parse math: ${}$", input);
-            String::from("<center>MATH PARSING ERROR</center>")
+    /// The escaped caption of the element with `number`, starting with the
+    /// same text that references to it use.
+    fn caption_html(self, number: usize, text: &str) -> String {
+        let reference = escape_html(&self.reference_text(number));
+        match text {
+            "" => reference,
+            _ => format!("{}: {}", reference, escape_html(text)),
         }
     }
 }
 
-impl ToHtml for MarkdownNode {
-    fn to_html(&self, base_path: &Path, fast: bool) -> String {
+/// A run of `*`, `_`, `~`, `=` or `^` while parsing a paragraph, which may
+/// become the start or end of emphasis or one of the other inline formats.
+struct Delimiter {
+    character: String,
+    /// Index of the first character of the run that is not used yet.
+    start: usize,
+    /// Number of characters of the run that are not used yet.
+    count: usize,
+    length: usize,
+    can_open: bool,
+    can_close: bool,
+}
+
+enum Inline {
+    Item(ParagraphItem),
+    Delimiter(Delimiter),
+}
+
+#[derive(Clone, PartialEq)]
+enum ListKind {
+    Bullet,
+    Ordered(String),
+}
+
+impl MarkdownNode {
+    pub fn span(&self) -> Span {
         match self {
-            MarkdownNode::Header(text, level) => format!("<h{}>{}</h{}>", level, text, level),
-            MarkdownNode::List(items) => {
-                let mut result: String = String::default();
-                result.push_str("<ul>");
-                for node in items {
-                    result.push_str(&format!("<li>{}</li>", node.to_html(base_path, fast)));
+            MarkdownNode::Header(_, _, _, span)
+            | MarkdownNode::Paragraph(_, _, span)
+            | MarkdownNode::List(_, span)
+            | MarkdownNode::OrderedList(_, _, span)
+            | MarkdownNode::Math(_, _, span)
+            | MarkdownNode::Code(_, _, _, _, span)
+            | MarkdownNode::Table(_, _, _, _, span)
+            | MarkdownNode::Figure(_, _, span)
+            | MarkdownNode::BlockQuote(_, span)
+            | MarkdownNode::PageBreak(span) => *span,
+        }
+    }
+}
+
+impl ParagraphItem {
+    pub fn span(&self) -> Span {
+        match self {
+            ParagraphItem::Text(_, span)
+            | ParagraphItem::Italic(_, span)
+            | ParagraphItem::Bold(_, span)
+            | ParagraphItem::Strikethrough(_, span)
+            | ParagraphItem::Highlight(_, span)
+            | ParagraphItem::Superscript(_, span)
+            | ParagraphItem::Subscript(_, span)
+            | ParagraphItem::Url(_, _, _, span)
+            | ParagraphItem::InlineMath(_, span)
+            | ParagraphItem::Image(_, _, _, span)
+            | ParagraphItem::InlineCode(_, span)
+            | ParagraphItem::Html(_, span)
+            | ParagraphItem::Reference(_, span)
+            | ParagraphItem::FootnoteReference(_, span) => *span,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseErrorKind {
+    Expected(String, String),
+    UnknownLanguage(String),
+    InvalidImage(String, String),
+    InvalidMath(String),
+    UnknownReference(String),
+    DuplicateLabel(String),
+    UnknownFootnote(String),
+    MathCache(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub kind: ParseErrorKind,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for ParseErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseErrorKind::Expected(expected, found) if found.is_empty() => {
+                write!(f, "expected `{}`, found end of input", expected)
+            }
+            ParseErrorKind::Expected(expected, found) => {
+                write!(
+                    f,
+                    "expected `{}`, found `{}`",
+                    expected,
+                    found.escape_default()
+                )
+            }
+            ParseErrorKind::UnknownLanguage(lang) => {
+                write!(
+                    f,
+                    "no syntax highlighting available for `{}`, using plain text",
+                    lang
+                )
+            }
+            ParseErrorKind::InvalidImage(url, reason) => {
+                write!(f, "failed to load image `{}`: {}", url, reason)
+            }
+            ParseErrorKind::InvalidMath(reason) => write!(f, "invalid math: {}", reason),
+            ParseErrorKind::UnknownReference(label) => {
+                write!(f, "reference to unknown label `{}`", label)
+            }
+            ParseErrorKind::DuplicateLabel(label) => {
+                write!(f, "label `{}` is already used", label)
+            }
+            ParseErrorKind::UnknownFootnote(label) => {
+                write!(f, "footnote `{}` is not defined", label)
+            }
+            ParseErrorKind::MathCache(reason) => {
+                write!(f, "failed to write math cache: {}", reason)
+            }
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.kind)
+    }
+}
+
+impl Error for ParseError {}
+
+impl ParseError {
+    pub fn new(kind: ParseErrorKind, span: Span) -> Self {
+        ParseError {
+            kind,
+            line: span.line,
+            column: span.column,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Position {
+    offset: usize,
+    line: usize,
+    column: usize,
+}
+
+/// Links nested deeper than this are kept as text, which bounds the
+/// recursion on input like `[[[[...`.
+const MAX_LINK_DEPTH: usize = 32;
+
+/// Block quotes, list items and footnotes nested deeper than this are parsed
+/// as paragraphs, which bounds the recursion on input like `>>>>...`.
+const MAX_BLOCK_DEPTH: usize = 64;
+
+pub struct Parser<'a> {
+    data: Vec<&'a str>,
+    positions: Vec<Position>,
+    index: usize,
+    footnotes: Vec<Footnote>,
+    /// Link reference definitions by normalized label, shared with the
+    /// parsers of nested blocks.
+    links: Rc<HashMap<String, (String, Option<String>)>>,
+    /// Number of link texts this parser is nested in.
+    link_depth: usize,
+    /// Number of block quotes, list items and footnotes this parser is
+    /// nested in.
+    block_depth: usize,
+    /// The `]` closing each `[` on the lines matched so far.
+    link_text_ends: HashMap<usize, Option<usize>>,
+}
+
+/// Settings shared by all nodes while converting a document to HTML.
+pub struct Renderer<'a> {
+    /// Directory that relative image paths are resolved against.
+    pub base_path: &'a Path,
+    /// Link images instead of embedding them.
+    pub fast: bool,
+    /// Drop raw HTML, links with a dangerous scheme like `javascript:` and
+    /// math commands that add links or styles.
+    pub safe: bool,
+    /// Fail on math that cannot be converted instead of warning about it.
+    pub strict: bool,
+    /// How math is converted, MathJax in fast mode and MathML otherwise.
+    pub math: MathOutput,
+    math_cache: MathCache,
+    labels: RefCell<HashMap<String, (LabelKind, usize)>>,
+    numbered: RefCell<HashMap<LabelKind, usize>>,
+    rendered: RefCell<HashMap<LabelKind, usize>>,
+    footnotes: RefCell<FootnoteState>,
+    highlighter: OnceCell<Highlighter>,
+    warnings: RefCell<Vec<ParseError>>,
+}
+
+impl<'a> Renderer<'a> {
+    pub fn new(base_path: &'a Path, fast: bool) -> Self {
+        Renderer {
+            base_path,
+            fast,
+            safe: false,
+            strict: false,
+            math: if fast {
+                MathOutput::MathJax
+            } else {
+                MathOutput::MathMl
+            },
+            math_cache: MathCache::new(),
+            labels: RefCell::new(HashMap::new()),
+            numbered: RefCell::new(HashMap::new()),
+            rendered: RefCell::new(HashMap::new()),
+            footnotes: RefCell::default(),
+            highlighter: OnceCell::new(),
+            warnings: RefCell::new(vec![]),
+        }
+    }
+
+    pub fn safe(mut self, safe: bool) -> Self {
+        self.safe = safe;
+        self
+    }
+
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    pub fn math(mut self, math: MathOutput) -> Self {
+        self.math = math;
+        self
+    }
+
+    /// Share rendered math with `cache` instead of a cache for this renderer.
+    pub fn with_math_cache(mut self, cache: MathCache) -> Self {
+        self.math_cache = cache;
+        self
+    }
+
+    /// Use `highlighter` for code blocks instead of loading the default one.
+    pub fn with_highlighter(mut self, highlighter: Highlighter) -> Self {
+        self.highlighter = OnceCell::from(highlighter);
+        self
+    }
+
+    /// Returns the highlighter, which is only loaded once the first code
+    /// block is rendered.
+    pub fn highlighter(&self) -> &Highlighter {
+        self.highlighter.get_or_init(Highlighter::new)
+    }
+
+    /// Records a problem that did not stop the document from rendering.
+    pub fn warn(&self, warning: ParseError) {
+        self.warnings.borrow_mut().push(warning);
+    }
+
+    /// Returns the warnings collected since the last call.
+    pub fn take_warnings(&self) -> Vec<ParseError> {
+        self.warnings.replace(vec![])
+    }
+
+    /// Numbers the equations, figures, tables and listings in `nodes`, so
+    /// references to their labels can be resolved. Numbering continues over
+    /// multiple calls, which allows references between the files of a
+    /// folder as long as they are rendered in the same order.
+    pub fn add_labels(&self, nodes: &[MarkdownNode]) {
+        for node in nodes {
+            match node {
+                MarkdownNode::Math(_, Some(label), span) => {
+                    self.add_label(LabelKind::Equation, Some(label), *span)
                 }
-                result.push_str("</ul>");
-                result
+                MarkdownNode::Figure(_, label, span) => {
+                    self.add_label(LabelKind::Figure, label.as_ref(), *span)
+                }
+                MarkdownNode::Table(_, _, _, Some(caption), _) => {
+                    self.add_label(LabelKind::Table, caption.label.as_ref(), caption.span)
+                }
+                MarkdownNode::Code(_, _, _, Some(caption), _) => {
+                    self.add_label(LabelKind::Listing, caption.label.as_ref(), caption.span)
+                }
+                MarkdownNode::BlockQuote(children, _) => self.add_labels(children),
+                MarkdownNode::List(items, _) | MarkdownNode::OrderedList(items, _, _) => {
+                    for item in items {
+                        self.add_labels(&item.children);
+                    }
+                }
+                _ => {}
             }
-            MarkdownNode::Math(math) => {
-                if fast {
-                    format!("<center>${}$</center>", math)
-                } else {
-                    format!("<center>{}</center>", tex_to_svg(math, false))
+        }
+    }
+
+    /// Numbers the labels of a whole document like `add_labels`, including
+    /// the ones in its footnotes in the order `render_document` renders them.
+    pub fn add_document_labels(&self, nodes: &[MarkdownNode], footnotes: &[Footnote]) {
+        self.add_labels(nodes);
+
+        let mut references = vec![];
+        footnote_references(nodes, &mut references);
+
+        // Footnotes are rendered in order of first use, referencing another
+        // footnote from a footnote adds it to the end
+        let mut used: Vec<&Footnote> = vec![];
+        let mut i = 0;
+        loop {
+            for label in references.drain(..) {
+                if used.iter().any(|x| x.label == label) {
+                    continue;
+                }
+                if let Some(footnote) = footnotes.iter().find(|x| x.label == label) {
+                    used.push(footnote);
                 }
             }
-            MarkdownNode::Code(lang, code) => {
-                let ss = SyntaxSet::load_defaults_newlines();
-                let ts = ThemeSet::load_defaults();
-                let theme = &ts.themes["base16-ocean.dark"];
 
-                let syntax = if lang.trim().is_empty() {
-                    ss.find_syntax_plain_text()
-                } else {
-                    ss.find_syntax_by_token(&lang.to_lowercase())
-                        .expect(&format!("Failed to load syntax for {}", lang))
+            let footnote = match used.get(i) {
+                Some(x) => x,
+                None => break,
+            };
+            self.add_labels(&footnote.children);
+            footnote_references(&footnote.children, &mut references);
+            i += 1;
+        }
+    }
+
+    fn add_label(&self, kind: LabelKind, label: Option<&String>, span: Span) {
+        let mut numbered = self.numbered.borrow_mut();
+        let number = numbered.entry(kind).or_insert(0);
+        *number += 1;
+
+        let label = match label {
+            Some(x) => x,
+            None => return,
+        };
+
+        if self.labels.borrow().contains_key(label) {
+            self.warn(ParseError::new(
+                ParseErrorKind::DuplicateLabel(label.clone()),
+                span,
+            ));
+            return;
+        }
+
+        self.labels
+            .borrow_mut()
+            .insert(label.clone(), (kind, *number));
+    }
+
+    /// Returns the number of the next element of `kind` being rendered,
+    /// together with the `id` attribute for its label. Only the element a
+    /// label was first given to gets the id, later duplicates get none.
+    fn next_number(&self, kind: LabelKind, label: Option<&String>) -> (usize, String) {
+        let mut rendered = self.rendered.borrow_mut();
+        let number = rendered.entry(kind).or_insert(0);
+        *number += 1;
+
+        let id = match label {
+            Some(x) if self.label(x).is_none_or(|y| y == (kind, *number)) => {
+                format!(" id=\"{}\"", escape_html(x))
+            }
+            _ => String::default(),
+        };
+
+        (*number, id)
+    }
+
+    /// Converts `nodes` to HTML, call `add_labels` first to resolve
+    /// references.
+    pub fn render(&self, nodes: &[MarkdownNode]) -> Result<String, ParseError> {
+        let mut result = String::new();
+        for node in nodes {
+            result.push_str(&node.to_html(self)?);
+        }
+
+        Ok(result)
+    }
+
+    /// Converts a whole document to HTML, followed by a section with the
+    /// footnotes it references. Footnotes are numbered per document.
+    pub fn render_document(
+        &self,
+        nodes: &[MarkdownNode],
+        footnotes: &[Footnote],
+    ) -> Result<String, ParseError> {
+        {
+            let mut state = self.footnotes.borrow_mut();
+            state.defined = footnotes.iter().map(|x| x.label.clone()).collect();
+            state.used.clear();
+            state.documents += 1;
+        }
+
+        let mut result = self.render(nodes)?;
+        result.push_str(&self.render_footnotes(footnotes)?);
+        Ok(result)
+    }
+
+    /// Returns the link to the footnote with `label`, or `None` if the
+    /// current document does not define it.
+    fn footnote_reference(&self, label: &str) -> Option<String> {
+        let mut state = self.footnotes.borrow_mut();
+        if !state.defined.iter().any(|x| x == label) {
+            return None;
+        }
+
+        let number = match state.used.iter().position(|(x, _)| x == label) {
+            Some(i) => {
+                state.used[i].1 += 1;
+                i + 1
+            }
+            None => {
+                state.used.push((String::from(label), 1));
+                state.used.len()
+            }
+        };
+
+        let prefix = state.prefix();
+        let id = match state.used[number - 1].1 {
+            1 => format!("{}ref:{}", prefix, number),
+            x => format!("{}ref:{}:{}", prefix, number, x),
+        };
+
+        Some(format!(
+            "<sup class=\"footnote-ref\" id=\"{}\"><a href=\"#{}:{}\">{}</a></sup>",
+            id, prefix, number, number
+        ))
+    }
+
+    fn render_footnotes(&self, footnotes: &[Footnote]) -> Result<String, ParseError> {
+        let mut items = String::new();
+
+        // Footnotes can reference other footnotes, which adds them to the end
+        let mut i = 0;
+        loop {
+            let label = match self.footnotes.borrow().used.get(i) {
+                Some((label, _)) => label.clone(),
+                None => break,
+            };
+            let footnote = footnotes.iter().find(|x| x.label == label).unwrap();
+            let mut content = self.render(&footnote.children)?;
+
+            let state = self.footnotes.borrow();
+            let prefix = state.prefix();
+            let number = i + 1;
+            let mut links = String::new();
+            for reference in 1..=state.used[i].1 {
+                let id = match reference {
+                    1 => format!("{}ref:{}", prefix, number),
+                    x => format!("{}ref:{}:{}", prefix, number, x),
                 };
-                let processed_code = code.replace("&lt;", "<").replace("&gt;", ">");
+                links.push_str(&format!(
+                    " <a href=\"#{}\" class=\"footnote-backref\">\u{21a9}</a>",
+                    id
+                ));
+            }
 
-                highlighted_html_for_string(&processed_code, &ss, &syntax, theme)
+            match content.strip_suffix("</p>") {
+                Some(x) => content = format!("{}{}</p>", x, links),
+                None => content.push_str(&links),
             }
-            MarkdownNode::Paragraph(children, single_line) => {
-                let mut result: String = String::default();
+            items.push_str(&format!(
+                "<li id=\"{}:{}\">{}</li>",
+                prefix, number, content
+            ));
+            i += 1;
+        }
 
-                if !single_line {
-                    result.push_str("<p>");
-                }
-                for child in children {
-                    result.push_str(child.to_html(base_path, fast).as_str());
-                }
-                if !single_line {
-                    result.push_str("</p>");
-                }
+        if items.is_empty() {
+            return Ok(String::default());
+        }
 
-                result
+        Ok(format!(
+            "<section class=\"footnotes\"><hr><ol>{}</ol></section>",
+            items
+        ))
+    }
+
+    /// Returns the kind and number of the element with `label`.
+    pub fn label(&self, label: &str) -> Option<(LabelKind, usize)> {
+        self.labels.borrow().get(label).copied()
+    }
+
+    /// Converts math according to `self.math`. Math that fails to convert
+    /// is a warning and shows up as its TeX source, or an error in strict
+    /// mode.
+    fn render_math(&self, math: &str, display: bool, span: Span) -> Result<String, ParseError> {
+        if self.safe {
+            if let Some(command) = unsafe_tex_command(math) {
+                let reason = format!("\\{} is not allowed in safe mode", command);
+                return self.invalid_math(math, reason, span);
+            }
+        }
+
+        if self.math == MathOutput::MathJax {
+            return Ok(format!("${}$", escape_html(math)));
+        }
+
+        let key = format!("{}:{}:{}", self.math.converter(), display, math);
+        if let Some(x) = self.math_cache.get(&key) {
+            return Ok(x);
+        }
+
+        let result = match self.math {
+            MathOutput::Svg => tex_to_svg(math, !display),
+            _ => tex_to_mathml(math, display),
+        };
+
+        if let Ok(html) = &result {
+            if let Err(e) = self.math_cache.insert(&key, html.clone()) {
+                self.warn(ParseError::new(
+                    ParseErrorKind::MathCache(e.to_string()),
+                    span,
+                ));
+            }
+        }
+
+        result.or_else(|e| self.invalid_math(math, e.to_string(), span))
+    }
+
+    /// Reports math that can't be rendered, which is an error in strict mode
+    /// and otherwise shown as its source.
+    fn invalid_math(&self, math: &str, reason: String, span: Span) -> Result<String, ParseError> {
+        let error = ParseError::new(ParseErrorKind::InvalidMath(reason), span);
+        if self.strict {
+            return Err(error);
+        }
+        self.warn(error);
+
+        Ok(match self.math {
+            MathOutput::MathMl => format!(
+                "<math><merror><mtext>{}</mtext></merror></math>",
+                escape_html(math)
+            ),
+            _ => format!("<code>{}</code>", escape_html(math)),
+        })
+    }
+
+    fn allows_url(&self, url: &str) -> bool {
+        if !self.safe {
+            return true;
+        }
+
+        let url: String = url
+            .chars()
+            .filter(|c| !c.is_whitespace() && !c.is_control())
+            .collect::<String>()
+            .to_lowercase();
+
+        !(url.starts_with("javascript:")
+            || url.starts_with("vbscript:")
+            || (url.starts_with("data:") && !url.starts_with("data:image/")))
+    }
+}
+
+pub trait ToHtml {
+    fn to_html(&self, renderer: &Renderer) -> Result<String, ParseError>;
+}
+
+/// Escapes text so it can be used both as element content and inside a
+/// quoted attribute value.
+pub fn escape_html(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        match c {
+            '&' => result.push_str("&amp;"),
+            '<' => result.push_str("&lt;"),
+            '>' => result.push_str("&gt;"),
+            '"' => result.push_str("&quot;"),
+            '\'' => result.push_str("&#39;"),
+            _ => result.push(c),
+        }
+    }
+
+    result
+}
+
+fn image_error(url: &str, reason: impl ToString, span: Span) -> ParseError {
+    ParseError::new(
+        ParseErrorKind::InvalidImage(String::from(url), reason.to_string()),
+        span,
+    )
+}
+
+fn items_to_html(items: &[ParagraphItem], renderer: &Renderer) -> Result<String, ParseError> {
+    let mut result = String::new();
+    for item in items {
+        result.push_str(&item.to_html(renderer)?);
+    }
+
+    Ok(result)
+}
+
+impl ToHtml for ParagraphItem {
+    fn to_html(&self, renderer: &Renderer) -> Result<String, ParseError> {
+        Ok(match self {
+            ParagraphItem::Text(text, _) => escape_html(text),
+            ParagraphItem::Italic(children, _) => {
+                format!("<em>{}</em>", items_to_html(children, renderer)?)
+            }
+            ParagraphItem::Bold(children, _) => {
+                format!("<b>{}</b>", items_to_html(children, renderer)?)
+            }
+            ParagraphItem::Strikethrough(children, _) => {
+                format!("<del>{}</del>", items_to_html(children, renderer)?)
+            }
+            ParagraphItem::Highlight(children, _) => {
+                format!("<mark>{}</mark>", items_to_html(children, renderer)?)
+            }
+            ParagraphItem::Superscript(children, _) => {
+                format!("<sup>{}</sup>", items_to_html(children, renderer)?)
+            }
+            ParagraphItem::Subscript(children, _) => {
+                format!("<sub>{}</sub>", items_to_html(children, renderer)?)
+            }
+            ParagraphItem::Url(children, url, attributes, _) => {
+                let content = items_to_html(children, renderer)?;
+                if !renderer.allows_url(url) {
+                    return Ok(content);
+                }
+
+                format!(
+                    "<a href=\"{}\"{}>{}</a>",
+                    escape_html(url),
+                    attributes.to_html(renderer),
+                    content
+                )
+            }
+            ParagraphItem::InlineMath(math, span) => renderer.render_math(math, false, *span)?,
+            ParagraphItem::Image(url, alt_text, attributes, span) => {
+                if !renderer.allows_url(url) {
+                    return Ok(escape_html(alt_text));
+                }
+
+                if renderer.fast
+                    || url.contains("www.")
+                    || url.contains("http://")
+                    || url.contains("https://")
+                {
+                    return Ok(format!(
+                        "<img src=\"{}\" alt=\"{}\"{}>",
+                        escape_html(url),
+                        escape_html(alt_text),
+                        attributes.to_html(renderer)
+                    ));
+                }
+
+                let path = renderer.base_path.join(url);
+                let mut f = File::open(path).map_err(|e| image_error(url, e, *span))?;
+                let mut buffer = vec![];
+                f.read_to_end(&mut buffer)
+                    .map_err(|e| image_error(url, e, *span))?;
+                let image_data = base64::encode(buffer);
+
+                let extension = Path::new(url)
+                    .extension()
+                    .and_then(OsStr::to_str)
+                    .ok_or_else(|| image_error(url, "missing file extension", *span))?;
+
+                format!(
+                    "<img src=\"data:image/{};base64,{}\" alt=\"{}\"{}>",
+                    escape_html(extension),
+                    image_data,
+                    escape_html(alt_text),
+                    attributes.to_html(renderer)
+                )
+            }
+            ParagraphItem::InlineCode(code, _) => format!("<code>{}</code>", escape_html(code)),
+            ParagraphItem::FootnoteReference(label, span) => {
+                match renderer.footnote_reference(label) {
+                    Some(x) => x,
+                    None => {
+                        renderer.warn(ParseError::new(
+                            ParseErrorKind::UnknownFootnote(label.clone()),
+                            *span,
+                        ));
+                        format!("[^{}]", escape_html(label))
+                    }
+                }
+            }
+            ParagraphItem::Reference(label, span) => match renderer.label(label) {
+                Some((kind, number)) => format!(
+                    "<a href=\"#{}\">{}</a>",
+                    escape_html(label),
+                    kind.reference_text(number)
+                ),
+                None => {
+                    renderer.warn(ParseError::new(
+                        ParseErrorKind::UnknownReference(label.clone()),
+                        *span,
+                    ));
+                    format!("@{}", escape_html(label))
+                }
+            },
+            ParagraphItem::Html(html, _) => {
+                if renderer.safe {
+                    String::default()
+                } else {
+                    String::from(html)
+                }
+            }
+        })
+    }
+}
+
+impl ToHtml for ListItem {
+    fn to_html(&self, renderer: &Renderer) -> Result<String, ParseError> {
+        let mut result = String::from("<li>");
+        match self.checked {
+            Some(true) => result.push_str("<input type=\"checkbox\" checked disabled> "),
+            Some(false) => result.push_str("<input type=\"checkbox\" disabled> "),
+            None => {}
+        }
+        for child in &self.children {
+            result.push_str(&child.to_html(renderer)?);
+        }
+        result.push_str("</li>");
+        Ok(result)
+    }
+}
+
+impl ToHtml for MarkdownNode {
+    fn to_html(&self, renderer: &Renderer) -> Result<String, ParseError> {
+        Ok(match self {
+            MarkdownNode::Header(text, level, attributes, _) => format!(
+                "<h{}{}>{}</h{}>",
+                level,
+                attributes.to_html(renderer),
+                escape_html(text),
+                level
+            ),
+            MarkdownNode::List(items, _) => {
+                let mut result: String = String::default();
+                result.push_str("<ul>");
+                for item in items {
+                    result.push_str(&item.to_html(renderer)?);
+                }
+                result.push_str("</ul>");
+                result
+            }
+            MarkdownNode::OrderedList(items, first, _) => {
+                let mut result: String = String::default();
+                if *first == 1 {
+                    result.push_str("<ol>");
+                } else {
+                    result.push_str(&format!("<ol start=\"{}\">", first));
+                }
+                for item in items {
+                    result.push_str(&item.to_html(renderer)?);
+                }
+                result.push_str("</ol>");
+                result
+            }
+            MarkdownNode::BlockQuote(children, _) => {
+                let mut result = String::from("<blockquote>");
+                for child in children {
+                    result.push_str(&child.to_html(renderer)?);
+                }
+                result.push_str("</blockquote>");
+                result
+            }
+            MarkdownNode::Math(math, label, span) => {
+                let math = renderer.render_math(math, true, *span)?;
+
+                match label {
+                    Some(label) => {
+                        let (number, id) = renderer.next_number(LabelKind::Equation, Some(label));
+                        format!(
+                            "<center{}><span style=\"float: right\">({})</span>{}</center>",
+                            id, number, math
+                        )
+                    }
+                    None => format!("<center>{}</center>", math),
+                }
+            }
+            MarkdownNode::Code(lang, code, attributes, caption, span) => {
+                let highlighter = renderer.highlighter();
+
+                let html = match highlighter.highlight(lang, code) {
+                    Some(x) => x,
+                    None => {
+                        renderer.warn(ParseError::new(
+                            ParseErrorKind::UnknownLanguage(lang.clone()),
+                            *span,
+                        ));
+                        highlighter.highlight("", code).unwrap()
+                    }
+                };
+                let html = attributes.add_to(&html, "pre", renderer);
+
+                match caption {
+                    Some(caption) => {
+                        let (number, id) =
+                            renderer.next_number(LabelKind::Listing, caption.label.as_ref());
+                        format!(
+                            "<figure class=\"listing\"{}><figcaption>{}</figcaption>{}</figure>",
+                            id,
+                            LabelKind::Listing.caption_html(number, &caption.text),
+                            html
+                        )
+                    }
+                    None => html,
+                }
+            }
+            MarkdownNode::Figure(image, label, _) => {
+                let (number, id) = renderer.next_number(LabelKind::Figure, label.as_ref());
+                let caption = match image {
+                    ParagraphItem::Image(_, alt, _, _) => {
+                        LabelKind::Figure.caption_html(number, alt)
+                    }
+                    _ => LabelKind::Figure.caption_html(number, ""),
+                };
+
+                format!(
+                    "<figure{}>{}<figcaption>{}</figcaption></figure>",
+                    id,
+                    image.to_html(renderer)?,
+                    caption
+                )
+            }
+            MarkdownNode::Paragraph(children, single_line, _) => {
+                let mut result: String = String::default();
+
+                if !single_line {
+                    result.push_str("<p>");
+                }
+                for child in children {
+                    result.push_str(&child.to_html(renderer)?);
+                }
+                if !single_line {
+                    result.push_str("</p>");
+                }
+
+                result
+            }
+            MarkdownNode::Table(headers, rows, alignments, caption, _) => {
+                let mut header_html = String::default();
+
+                let style = |i: usize| alignments.get(i).map_or("", |x| x.style());
+
+                for (i, header) in headers.iter().enumerate() {
+                    header_html += &format!("<th{}>{}</th>", style(i), header.to_html(renderer)?);
+                }
+
+                let mut rows_html = String::new();
+                for row in rows {
+                    rows_html += "<tr>";
+                    for (i, cell) in row.iter().enumerate() {
+                        rows_html += &format!("<td{}>{}</td>", style(i), cell.to_html(renderer)?);
+                    }
+                    rows_html += "</tr>";
+                }
+                if !rows.is_empty() {
+                    rows_html = format!("<tbody>{}</tbody>", rows_html);
+                }
+
+                let (id, caption_html) = match caption {
+                    Some(caption) => {
+                        let (number, id) =
+                            renderer.next_number(LabelKind::Table, caption.label.as_ref());
+                        (
+                            id,
+                            format!(
+                                "<caption>{}</caption>",
+                                LabelKind::Table.caption_html(number, &caption.text)
+                            ),
+                        )
+                    }
+                    None => (String::default(), String::default()),
+                };
+
+                format!(
+                    "<table{}>{}<thead><tr>{}</tr></thead>{}</table>",
+                    id, caption_html, header_html, rows_html,
+                )
+            }
+            MarkdownNode::PageBreak(_) => {
+                String::from("<p style=\"page-break-after: always;\"</p>")
+            }
+        })
+    }
+}
+
+fn is_whitespace(string: String) -> bool {
+    string == " " || string == "\t"
+}
+
+fn is_digit(string: String) -> bool {
+    !string.is_empty() && string.chars().all(|c| c.is_ascii_digit())
+}
+
+fn is_newline(string: String) -> bool {
+    string == "\r\n" || string == "\n"
+}
+
+/// Whitespace as seen by emphasis, where the start and end of a line count
+/// as whitespace too.
+fn is_flanking_space(string: &str) -> bool {
+    string.chars().all(char::is_whitespace)
+}
+
+fn is_punctuation(string: &str) -> bool {
+    string
+        .chars()
+        .next()
+        .is_some_and(|c| !c.is_alphanumeric() && !c.is_whitespace())
+}
+
+/// Checks if `string` can be escaped with a backslash, which is true for all
+/// ASCII punctuation.
+fn is_escapable(string: String) -> bool {
+    string.len() == 1 && string.chars().all(|c| c.is_ascii_punctuation())
+}
+
+/// Normalizes a link label so that matching is case insensitive and
+/// ignores differences in whitespace.
+fn normalize_label(label: &str) -> String {
+    label
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+/// Splits trailing attributes like `{#intro .big}` off the text of a
+/// header or the info string of a code block.
+fn split_attributes(text: &str) -> (&str, Attributes) {
+    if let Some(inner) = text.strip_suffix('}') {
+        if let Some(open) = inner.rfind('{') {
+            if !inner[..open].ends_with('\\') {
+                if let Some(attributes) = Attributes::parse(&inner[open + 1..]) {
+                    return (inner[..open].trim_end(), attributes);
+                }
+            }
+        }
+    }
+
+    (text, Attributes::default())
+}
+
+/// Removes the backslashes of escaped punctuation in text that is not
+/// parsed any further, like headers.
+fn unescape(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match chars.peek() {
+            Some(x) if c == '\\' && x.is_ascii_punctuation() => {
+                result.push(*x);
+                chars.next();
+            }
+            _ => result.push(c),
+        }
+    }
+
+    result
+}
+
+impl<'a> Parser<'a> {
+    pub fn new(input: &'a str) -> Self {
+        let mut data = Vec::new();
+        let mut positions = Vec::new();
+        let mut line = 1;
+        let mut column = 1;
+
+        for (offset, grapheme) in UnicodeSegmentation::grapheme_indices(input, true) {
+            data.push(grapheme);
+            positions.push(Position {
+                offset,
+                line,
+                column,
+            });
+
+            if is_newline(String::from(grapheme)) {
+                line += 1;
+                column = 1;
+            } else {
+                column += 1;
+            }
+        }
+
+        positions.push(Position {
+            offset: input.len(),
+            line,
+            column,
+        });
+
+        let mut parser = Parser {
+            data,
+            positions,
+            index: 0,
+            footnotes: vec![],
+            links: Rc::default(),
+            link_depth: 0,
+            block_depth: 0,
+            link_text_ends: HashMap::new(),
+        };
+        parser.links = Rc::new(parser.link_definitions());
+        parser
+    }
+
+    fn eof(&mut self) -> bool {
+        self.index >= self.data.len()
+    }
+
+    fn peek(&mut self, index: usize) -> String {
+        match self.data.get(self.index + index) {
+            Some(x) => String::from(*x),
+            None => String::default(),
+        }
+    }
+
+    fn consume(&mut self) -> &str {
+        if self.eof() {
+            return "";
+        }
+
+        let result = self.data[self.index];
+        self.index += 1;
+        result
+    }
+
+    fn assert_consume(&mut self, expected: &str) -> Result<(), ParseError> {
+        let c = self.peek(0);
+
+        if c != expected {
+            return Err(self.error(ParseErrorKind::Expected(String::from(expected), c)));
+        }
+
+        self.consume();
+        Ok(())
+    }
+
+    fn go_back(&mut self, count: usize) {
+        self.index -= count;
+    }
+
+    fn span_from(&self, start: usize) -> Span {
+        self.span_between(start, self.index)
+    }
+
+    fn span_between(&self, start: usize, end: usize) -> Span {
+        let begin = self.positions[start];
+        let end = if end > start {
+            self.positions[end - 1].offset + self.data[end - 1].len()
+        } else {
+            begin.offset
+        };
+
+        Span {
+            start: begin.offset,
+            end,
+            line: begin.line,
+            column: begin.column,
+        }
+    }
+
+    fn error(&self, kind: ParseErrorKind) -> ParseError {
+        ParseError::new(kind, self.span_from(self.index))
+    }
+
+    fn consume_chars(&mut self, character: &str) -> String {
+        let mut result = String::default();
+
+        loop {
+            if self.eof() {
+                break;
+            }
+
+            let c = self.peek(0);
+
+            if c != character {
+                break;
+            }
+
+            result.push_str(self.consume());
+        }
+
+        result
+    }
+
+    fn consume_until(&mut self, f: fn(String) -> bool) -> String {
+        let mut result = String::default();
+
+        loop {
+            if self.eof() {
+                break;
+            }
+
+            let c = self.peek(0);
+
+            if f(c) {
+                break;
+            }
+
+            result.push_str(self.consume());
+        }
+
+        result
+    }
+
+    fn skip_whitespace(&mut self) {
+        self.consume_until(|c| c != " " && c != "\t" && c != "\n" && c != "\r\n");
+    }
+
+    fn parse_header(&mut self) -> Result<Option<MarkdownNode>, ParseError> {
+        let start = self.index;
+        let hashtags = self.consume_chars("#");
+
+        if !is_whitespace(self.peek(0)) {
+            self.go_back(hashtags.len());
+            return Ok(None);
+        }
+
+        self.consume();
+        let line = self.consume_until(is_newline);
+        let (text, attributes) = split_attributes(line.trim());
+
+        Ok(Some(MarkdownNode::Header(
+            unescape(text),
+            hashtags.len(),
+            attributes,
+            self.span_from(start),
+        )))
+    }
+
+    /// Returns the kind, number and length of a list marker like `*`, `12.`
+    /// or `3)` at the cursor.
+    fn peek_list_marker(&mut self) -> Option<(ListKind, usize, usize)> {
+        let c = self.peek(0);
+        if (c == "*" || c == "-") && is_whitespace(self.peek(1)) {
+            return Some((ListKind::Bullet, 0, 1));
+        }
+
+        let mut digits = String::default();
+        while digits.len() < 9 && is_digit(self.peek(digits.len())) {
+            digits += &self.peek(digits.len());
+        }
+
+        let delimiter = self.peek(digits.len());
+        if digits.is_empty()
+            || (delimiter != "." && delimiter != ")")
+            || !is_whitespace(self.peek(digits.len() + 1))
+        {
+            return None;
+        }
+
+        Some((
+            ListKind::Ordered(delimiter),
+            digits.parse().unwrap(),
+            digits.len() + 1,
+        ))
+    }
+
+    fn line_end(&self, index: usize) -> usize {
+        (index..self.data.len())
+            .find(|i| is_newline(String::from(self.data[*i])))
+            .unwrap_or(self.data.len())
+    }
+
+    /// Returns the width of the indentation of the line starting at `index`
+    /// together with the index of its first non whitespace character.
+    fn line_indent(&self, index: usize) -> (usize, usize) {
+        let mut width = 0;
+        let mut index = index;
+
+        while index < self.data.len() {
+            match self.data[index] {
+                " " => width += 1,
+                "\t" => width += 4,
+                _ => break,
+            }
+            index += 1;
+        }
+
+        (width, index)
+    }
+
+    /// Returns the width of the whitespace in front of `index` on its line.
+    fn indent_before(&self, index: usize) -> usize {
+        let mut line_start = index;
+        while line_start > 0 && is_whitespace(String::from(self.data[line_start - 1])) {
+            line_start -= 1;
+        }
+
+        self.line_indent(line_start).0
+    }
+
+    /// Creates a parser over a subset of the graphemes of this parser, used to
+    /// parse nested blocks after their indentation or prefix has been removed.
+    fn sub_parser(&self, indices: &[usize]) -> Parser<'a> {
+        let mut positions: Vec<Position> = indices.iter().map(|i| self.positions[*i]).collect();
+        positions.push(match indices.last() {
+            Some(last) => self.positions[last + 1],
+            None => self.positions[self.index],
+        });
+
+        Parser {
+            data: indices.iter().map(|i| self.data[*i]).collect(),
+            positions,
+            index: 0,
+            footnotes: vec![],
+            links: Rc::clone(&self.links),
+            link_depth: self.link_depth,
+            block_depth: self.block_depth,
+            link_text_ends: HashMap::new(),
+        }
+    }
+
+    /// Collects the indices of a block whose first line starts at `index`.
+    /// Following lines belong to it as long as they are indented at least
+    /// `content_indent`, which is stripped. Returns the indices together
+    /// with the end of the block.
+    fn indented_block(&self, index: usize, content_indent: usize) -> (Vec<usize>, usize) {
+        let mut end = self.line_end(index);
+        let mut indices: Vec<usize> = (index..end).collect();
+        let mut blank_lines: Vec<usize> = vec![];
+        let mut line_start = end + 1;
+
+        while line_start < self.data.len() {
+            let (width, content_start) = self.line_indent(line_start);
+            let line_end = self.line_end(content_start);
+
+            if content_start == line_end {
+                if line_end < self.data.len() {
+                    blank_lines.push(line_end);
+                }
+                line_start = line_end + 1;
+                continue;
+            }
+            if width < content_indent {
+                break;
+            }
+
+            // Only strip the indentation of the block itself so nested blocks
+            // keep their relative indentation
+            let mut content_start = line_start;
+            let mut stripped = 0;
+            while stripped < content_indent {
+                stripped += if self.data[content_start] == "\t" {
+                    4
+                } else {
+                    1
+                };
+                content_start += 1;
+            }
+
+            indices.push(end);
+            indices.append(&mut blank_lines);
+            indices.extend(content_start..line_end);
+            end = line_end;
+            line_start = line_end + 1;
+        }
+
+        (indices, end)
+    }
+
+    fn parse_list_item(&mut self, indent: usize) -> Result<Option<ListItem>, ParseError> {
+        let start = self.index;
+        let marker_length = match self.peek_list_marker() {
+            Some((_, _, length)) => length,
+            None => return Ok(None),
+        };
+        self.index += marker_length;
+
+        let spaces = self.consume_until(|c| !is_whitespace(c)).len();
+        let content_indent = indent + marker_length + if spaces > 4 { 1 } else { spaces };
+
+        // A task list item starts with `[ ]` or `[x]` followed by whitespace
+        let after = self.peek(3);
+        let checked = match self.data.get(self.index..self.index + 3) {
+            _ if !is_flanking_space(&after) => None,
+            Some(["[", " ", "]"]) => Some(false),
+            Some(["[", "x", "]"]) | Some(["[", "X", "]"]) => Some(true),
+            _ => None,
+        };
+        if checked.is_some() {
+            self.index += 3;
+            self.consume_until(|c| !is_whitespace(c));
+        }
+
+        let (indices, end) = self.indented_block(self.index, content_indent);
+        self.index = end;
+
+        let mut parser = self.sub_parser(&indices);
+        parser.block_depth += 1;
+        let mut children = vec![];
+        if let Some(node) = parser.next_node(true)? {
+            children.push(node);
+        }
+        while let Some(node) = parser.next_node(false)? {
+            children.push(node);
+        }
+        self.footnotes.append(&mut parser.footnotes);
+
+        Ok(Some(ListItem {
+            children,
+            checked,
+            span: self.span_from(start),
+        }))
+    }
+
+    fn parse_list(&mut self) -> Result<Option<MarkdownNode>, ParseError> {
+        if self.block_depth >= MAX_BLOCK_DEPTH {
+            return Ok(None);
+        }
+        let start = self.index;
+
+        let (kind, first, _) = match self.peek_list_marker() {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+
+        let mut items: Vec<ListItem> = Vec::new();
+        let mut end = self.index;
+
+        loop {
+            match self.peek_list_marker() {
+                Some((current_kind, _, _)) if current_kind == kind => {}
+                _ => break,
+            }
+
+            let item_indent = self.indent_before(self.index);
+            match self.parse_list_item(item_indent)? {
+                Some(item) => items.push(item),
+                None => break,
+            }
+            end = self.index;
+
+            // Sibling items may be separated by blank lines
+            self.skip_whitespace();
+        }
+        self.index = end;
+
+        let span = self.span_between(start, end);
+        Ok(Some(match kind {
+            ListKind::Bullet => MarkdownNode::List(items, span),
+            ListKind::Ordered(_) => MarkdownNode::OrderedList(items, first, span),
+        }))
+    }
+
+    /// Checks if the text at the cursor starts with `text`.
+    fn peek_str(&self, text: &str) -> bool {
+        text.graphemes(true)
+            .enumerate()
+            .all(|(i, c)| self.data.get(self.index + i) == Some(&c))
+    }
+
+    fn rest_of_line_is_blank(&self) -> bool {
+        (self.index..self.line_end(self.index)).all(|i| is_whitespace(String::from(self.data[i])))
+    }
+
+    /// Parses a label like `{#eq:energy}` after a block, leaving the cursor
+    /// untouched when there is none.
+    fn parse_label(&mut self) -> Option<String> {
+        let start = self.index;
+        self.consume_until(|c| c != " " && c != "\t");
+
+        if self.peek(0) == "{" && self.peek(1) == "#" {
+            self.index += 2;
+            let label =
+                self.consume_until(|c| c == "}" || is_whitespace(c.clone()) || is_newline(c));
+            if !label.is_empty() && self.peek(0) == "}" {
+                self.consume();
+                return Some(label);
+            }
+        }
+
+        self.index = start;
+        None
+    }
+
+    /// Collects the link reference definitions of the whole document up
+    /// front, so links can refer to definitions further down.
+    fn link_definitions(&mut self) -> HashMap<String, (String, Option<String>)> {
+        let mut links = HashMap::new();
+        let mut in_code = false;
+        let mut line_start = 0;
+
+        while line_start < self.data.len() {
+            // Definitions can be nested in block quotes and list items
+            self.index = line_start;
+            self.consume_until(|c| c != ">" && !is_whitespace(c));
+            if let Some((_, _, length)) = self.peek_list_marker() {
+                self.index += length;
+                self.consume_until(|c| !is_whitespace(c));
+            }
+
+            if self.peek_str("```") {
+                in_code = !in_code;
+            } else if !in_code {
+                if let Some((label, url, title)) = self.parse_link_definition() {
+                    // The first definition of a label wins
+                    links.entry(label).or_insert((url, title));
+                }
+            }
+
+            line_start = self.line_end(line_start) + 1;
+        }
+
+        self.index = 0;
+        links
+    }
+
+    /// Parses a link reference definition like `[id]: https://... "title"`
+    /// on its own line, leaving the cursor untouched when there is none.
+    fn parse_link_definition(&mut self) -> Option<(String, String, Option<String>)> {
+        let start = self.index;
+        let definition = self.link_definition();
+        if definition.is_none() {
+            self.index = start;
+        }
+
+        definition
+    }
+
+    fn link_definition(&mut self) -> Option<(String, String, Option<String>)> {
+        if self.peek(0) != "[" || self.peek(1) == "^" {
+            return None;
+        }
+        self.consume();
+
+        let label = self.consume_until(|c| c == "]" || c == "[" || is_newline(c));
+        if label.trim().is_empty() || self.peek(0) != "]" || self.peek(1) != ":" {
+            return None;
+        }
+        self.index += 2;
+        self.consume_until(|c| !is_whitespace(c));
+
+        let url = if self.peek(0) == "<" {
+            self.consume();
+            let url = self.consume_until(|c| c == ">" || is_newline(c));
+            if self.peek(0) != ">" {
+                return None;
+            }
+            self.consume();
+            url
+        } else {
+            let url = self.consume_until(|c| is_whitespace(c.clone()) || is_newline(c));
+            if url.is_empty() {
+                return None;
+            }
+            url
+        };
+
+        // The title is separated from the url by whitespace and quoted with
+        // `"`, `'` or parentheses
+        let spaces = self.consume_until(|c| !is_whitespace(c));
+        let title = match spaces.is_empty() {
+            true => None,
+            false => self.parse_title(),
+        };
+
+        if !self.rest_of_line_is_blank() {
+            return None;
+        }
+        self.index = self.line_end(self.index);
+
+        Some((normalize_label(&label), unescape(&url), title))
+    }
+
+    /// Resolves a reference after the `]` of a link or image with `text`,
+    /// either a full `[text][id]`, collapsed `[text][]` or shortcut `[text]`
+    /// reference. Leaves the cursor untouched when the label is not defined.
+    fn parse_link_reference(&mut self, text: &str) -> Option<(String, Option<String>)> {
+        let start = self.index;
+        let mut label = String::from(text);
+
+        if self.peek(0) == "[" {
+            self.consume();
+            let id = self.consume_until(|c| c == "]" || c == "[" || is_newline(c));
+            if self.peek(0) != "]" {
+                self.index = start;
+                return None;
+            }
+            self.consume();
+
+            if !id.is_empty() {
+                label = id;
+            }
+        }
+
+        let link = self.links.get(&normalize_label(&label)).cloned();
+        if link.is_none() {
+            self.index = start;
+        }
+
+        link
+    }
+
+    /// Parses a footnote definition like `[^1]: text`, following lines that
+    /// are indented by four spaces belong to the footnote as well.
+    fn parse_footnote(&mut self) -> Result<Option<Footnote>, ParseError> {
+        if self.block_depth >= MAX_BLOCK_DEPTH {
+            return Ok(None);
+        }
+        let start = self.index;
+        self.index += 2;
+
+        let label = self.consume_until(|c| c == "]" || is_whitespace(c.clone()) || is_newline(c));
+        if label.is_empty() || self.peek(0) != "]" || self.peek(1) != ":" {
+            self.index = start;
+            return Ok(None);
+        }
+        self.index += 2;
+        self.consume_until(|c| !is_whitespace(c));
+
+        let (indices, end) = self.indented_block(self.index, 4);
+        self.index = end;
+
+        let mut parser = self.sub_parser(&indices);
+        parser.block_depth += 1;
+        let mut children = vec![];
+        while let Some(node) = parser.next_node(false)? {
+            children.push(node);
+        }
+        self.footnotes.append(&mut parser.footnotes);
+
+        Ok(Some(Footnote {
+            label,
+            children,
+            span: self.span_from(start),
+        }))
+    }
+
+    /// Parses a caption like `Table: Results {#tbl:results}` starting with
+    /// `prefix` on the next non blank line.
+    fn parse_caption(&mut self, prefix: &str) -> Option<Caption> {
+        let start = self.index;
+        self.skip_whitespace();
+
+        if !self.peek_str(prefix) {
+            self.index = start;
+            return None;
+        }
+
+        let caption_start = self.index;
+        self.index += prefix.graphemes(true).count();
+
+        let mut text = String::default();
+        let mut label = None;
+        loop {
+            text += &self.consume_until(|c| c == "{" || is_newline(c));
+            if self.eof() || is_newline(self.peek(0)) {
+                break;
+            }
+
+            let label_start = self.index;
+            label = self.parse_label();
+            if label.is_some() && self.rest_of_line_is_blank() {
+                break;
+            }
+            label = None;
+            self.index = label_start;
+            text += self.consume();
+        }
+
+        Some(Caption {
+            text: String::from(text.trim()),
+            label,
+            span: self.span_from(caption_start),
+        })
+    }
+
+    /// Parses an image alone on a line, with an optional label like
+    /// `{#fig:plot}`, as a figure.
+    fn parse_figure(&mut self) -> Result<Option<MarkdownNode>, ParseError> {
+        let start = self.index;
+        let mut image = self.parse_image()?;
+        let mut label = self.parse_label();
+
+        if !matches!(image, ParagraphItem::Image(..)) || !self.rest_of_line_is_blank() {
+            self.index = start;
+            return Ok(None);
+        }
+
+        // An id in the attributes of the image labels the figure as well
+        if let ParagraphItem::Image(_, _, attributes, _) = &mut image {
+            if label.is_none() {
+                label = attributes.id.take();
+            }
+        }
+
+        Ok(Some(MarkdownNode::Figure(
+            image,
+            label,
+            self.span_from(start),
+        )))
+    }
+
+    /// Returns the length of a footnote reference like `[^1]` at the cursor.
+    fn peek_footnote_reference(&self) -> Option<usize> {
+        let mut length = 2;
+        loop {
+            match self.data.get(self.index + length) {
+                Some(&"]") if length > 2 => return Some(length + 1),
+                Some(c) if !is_flanking_space(c) && *c != "[" && *c != "]" => length += 1,
+                _ => return None,
+            }
+        }
+    }
+
+    /// Returns the length of a reference like `@eq:energy` at the cursor.
+    /// An `@` inside a word, like in an email address, is not a reference.
+    fn peek_reference(&self) -> Option<usize> {
+        let is_word = |i: usize| {
+            self.data.get(i).is_some_and(|c| {
+                c.chars()
+                    .all(|c| c.is_alphanumeric() || c == '_' || c == '-')
+            })
+        };
+
+        if self.index > 0 && is_word(self.index - 1) {
+            return None;
+        }
+
+        let mut length = 1;
+        while is_word(self.index + length) && self.data[self.index + length] != "-" {
+            length += 1;
+        }
+        if length == 1 || self.data.get(self.index + length) != Some(&":") {
+            return None;
+        }
+        length += 1;
+
+        // Allow `:` and `.` inside the name, but not at its end
+        let name_start = length;
+        loop {
+            if is_word(self.index + length) {
+                length += 1;
+            } else if matches!(self.data.get(self.index + length), Some(&":") | Some(&"."))
+                && is_word(self.index + length + 1)
+            {
+                length += 2;
+            } else {
+                break;
+            }
+        }
+
+        if length == name_start {
+            return None;
+        }
+        Some(length)
+    }
+
+    /// Parses a `$$` display math block, which may span several lines. If
+    /// text follows the closing `$$`, the line is a paragraph instead.
+    fn parse_math(&mut self) -> Result<Option<MarkdownNode>, ParseError> {
+        let start = self.index;
+        self.index += 2;
+
+        let mut math = String::default();
+        while !self.peek_str("$$") {
+            if self.eof() {
+                return Err(self.error(ParseErrorKind::Expected(
+                    String::from("$$"),
+                    String::default(),
+                )));
             }
-            MarkdownNode::Table(headers, data) => {
-                let mut header_html = String::default();
+            math.push_str(self.consume());
+        }
+        self.index += 2;
 
-                for header in headers {
-                    header_html += &format!("<th>{}</th>", header.to_html(base_path, fast));
-                }
+        let span = self.span_from(start);
+        let label = self.parse_label();
+        if !self.rest_of_line_is_blank() {
+            self.index = start;
+            return Ok(None);
+        }
 
-                let mut data_html = String::new();
-                for i in 0..data.len() {
-                    if i % headers.len() == 0 {
-                        data_html += "<tr>";
-                    }
+        Ok(Some(MarkdownNode::Math(
+            String::from(math.trim()),
+            label,
+            span,
+        )))
+    }
 
-                    data_html += &format!("<td>{}</td>", data[i].to_html(base_path, fast));
+    /// Parses a LaTeX environment like `\begin{align} ... \end{align}` at
+    /// the start of a line as display math.
+    fn parse_math_environment(&mut self) -> Result<Option<MarkdownNode>, ParseError> {
+        let start = self.index;
+        self.index += "\\begin{".len();
 
-                    if i % headers.len() == headers.len() - 1 {
-                        data_html += "</tr>";
-                    }
-                }
+        let name = self.consume_until(|c| c == "}" || is_whitespace(c.clone()) || is_newline(c));
+        if name.is_empty() || self.peek(0) != "}" {
+            self.index = start;
+            return Ok(None);
+        }
 
-                format!(
-                    "<table><tr>{}</tr><tr>{}</tr></table>",
-                    header_html, data_html,
-                )
+        let end = format!("\\end{{{}}}", name);
+        while !self.peek_str(&end) {
+            if self.eof() {
+                return Err(self.error(ParseErrorKind::Expected(end, String::default())));
             }
-            MarkdownNode::PageBreak() => String::from("<p style=\"page-break-after: always;\"</p>"),
+            self.consume();
         }
+        self.index += end.graphemes(true).count();
+
+        let math = self.data[start..self.index].concat();
+        let span = self.span_from(start);
+        let label = self.parse_label();
+        Ok(Some(MarkdownNode::Math(math, label, span)))
     }
-}
 
-fn is_whitespace(string: String) -> bool {
-    string == " " || string == "\t"
-}
+    /// Checks if the cursor is at an autolink like `<https://example.com>` or
+    /// `<user@example.com>`, other uses of `<` are treated as text.
+    fn is_autolink(&mut self) -> bool {
+        let mut url = String::default();
 
-fn is_newline(string: String) -> bool {
-    string == "\r\n" || string == "\n"
-}
+        for i in 1.. {
+            let c = self.peek(i);
+            if c == ">" {
+                break;
+            }
+            if c.is_empty() || c == "<" || is_whitespace(c.clone()) || is_newline(c.clone()) {
+                return false;
+            }
+            url += &c;
+        }
 
-fn preprocess_html(string: String) -> String {
-    string.replace("<", "&lt;").replace(">", "&gt;")
-}
+        url.contains(':') || url.contains('@')
+    }
 
-impl<'a> Parser<'a> {
-    pub fn new(input: &'a str) -> Self {
-        Parser {
-            data: UnicodeSegmentation::graphemes(input, true).collect::<Vec<&str>>(),
-            index: 0,
+    /// Returns the length of the inline HTML tag or comment at the cursor.
+    fn peek_html_tag(&mut self) -> Option<usize> {
+        let mut length = 1;
+
+        if self.peek(1) == "!" && self.peek(2) == "-" && self.peek(3) == "-" {
+            length = 4;
+            loop {
+                if self.peek(length) == "-"
+                    && self.peek(length + 1) == "-"
+                    && self.peek(length + 2) == ">"
+                {
+                    return Some(length + 3);
+                }
+
+                let c = self.peek(length);
+                if c.is_empty() || is_newline(c) {
+                    return None;
+                }
+                length += 1;
+            }
         }
-    }
 
-    fn eof(&mut self) -> bool {
-        self.index >= self.data.len()
-    }
+        if self.peek(length) == "/" {
+            length += 1;
+        }
+        let c = self.peek(length);
+        if c.is_empty() || !c.chars().all(|c| c.is_ascii_alphabetic()) {
+            return None;
+        }
 
-    fn peek(&mut self, index: usize) -> String {
-        self.data[self.index + index].to_owned()
+        loop {
+            let c = self.peek(length);
+            if c.is_empty() || c == "<" || is_newline(c.clone()) {
+                return None;
+            }
+            length += 1;
+            if c == ">" {
+                return Some(length);
+            }
+        }
     }
 
-    fn consume(&mut self) -> &str {
-        let result = self.data[self.index];
-        self.index += 1;
-        result
-    }
+    fn parse_inline_html(&mut self) -> ParagraphItem {
+        let start = self.index;
+        let mut html = String::default();
 
-    fn go_back(&mut self, count: usize) {
-        self.index -= count;
+        for _ in 0..self.peek_html_tag().unwrap_or(1) {
+            html.push_str(self.consume());
+        }
+
+        ParagraphItem::Html(html, self.span_from(start))
     }
 
-    fn consume_chars(&mut self, character: &str) -> String {
-        let mut result = String::default();
+    fn parse_url(&mut self) -> Result<ParagraphItem, ParseError> {
+        let start = self.index;
+        self.consume();
 
-        loop {
-            if self.eof() {
+        let name = self.consume_until(|c| c == ">" || is_newline(c));
+        let url = if !name.contains(':') && name.contains('@') {
+            format!("mailto:{}", name)
+        } else {
+            name.clone()
+        };
+
+        self.assert_consume(">")?;
+
+        let span = self.span_from(start);
+        Ok(ParagraphItem::Url(
+            vec![ParagraphItem::Text(
+                name,
+                self.span_between(start + 1, self.index - 1),
+            )],
+            url,
+            Attributes::default(),
+            span,
+        ))
+    }
+
+    fn parse_block_quote(&mut self) -> Result<Option<MarkdownNode>, ParseError> {
+        if self.block_depth >= MAX_BLOCK_DEPTH {
+            return Ok(None);
+        }
+        let start = self.index;
+        let mut indices: Vec<usize> = vec![];
+        let mut end = self.index;
+        let mut line_start = self.index;
+
+        // Every line of the quote starts with `>`, which is removed together
+        // with a single following space before parsing the content
+        while line_start < self.data.len() {
+            let (width, mut content_start) = self.line_indent(line_start);
+            if (line_start != start && width > 3)
+                || content_start == self.data.len()
+                || self.data[content_start] != ">"
+            {
                 break;
             }
 
-            let c = self.peek(0);
+            content_start += 1;
+            if content_start < self.data.len() && self.data[content_start] == " " {
+                content_start += 1;
+            }
 
-            if c != character {
-                break;
+            let line_end = self.line_end(content_start);
+            if line_start != start {
+                indices.push(end);
             }
+            indices.extend(content_start..line_end);
+            end = line_end;
+            line_start = line_end + 1;
+        }
 
-            result.push_str(self.consume());
+        self.index = end;
+
+        let mut parser = self.sub_parser(&indices);
+        parser.block_depth += 1;
+        let mut children = vec![];
+        while let Some(node) = parser.next_node(false)? {
+            children.push(node);
         }
+        self.footnotes.append(&mut parser.footnotes);
 
-        result
+        Ok(Some(MarkdownNode::BlockQuote(
+            children,
+            self.span_from(start),
+        )))
     }
 
-    fn consume_until(&mut self, f: fn(String) -> bool) -> String {
-        let mut result = String::default();
+    fn parse_code(&mut self) -> Result<Option<MarkdownNode>, ParseError> {
+        let start = self.index;
+        let backticks = self.consume_chars("`");
+        if backticks.len() < 3 {
+            self.go_back(backticks.len());
+            return Ok(None);
+        }
 
-        loop {
-            if self.eof() {
-                break;
+        let info = self.consume_until(is_newline);
+        let (lang, attributes) = split_attributes(info.trim());
+        let lang = lang.to_lowercase();
+
+        // The block ends at a line with at least as many backticks as the
+        // opening fence, so the code itself can contain backticks
+        let code_start = (self.index + 1).min(self.data.len());
+        let mut line_start = code_start;
+        let closing = loop {
+            if line_start >= self.data.len() {
+                self.index = self.data.len();
+                return Err(self.error(ParseErrorKind::Expected(backticks, String::default())));
             }
 
-            let c = self.peek(0);
-
-            if f(c) {
-                break;
+            let (width, fence_start) = self.line_indent(line_start);
+            let fence_end = (fence_start..self.data.len())
+                .find(|i| self.data[*i] != "`")
+                .unwrap_or(self.data.len());
+            self.index = fence_end;
+            if width < 4
+                && fence_end - fence_start >= backticks.len()
+                && self.rest_of_line_is_blank()
+            {
+                break line_start;
             }
 
-            result.push_str(self.consume());
-        }
+            line_start = self.line_end(line_start) + 1;
+        };
 
-        result
-    }
+        let code = self.data[code_start..closing.max(code_start)].concat();
+        let code = String::from(code.trim());
 
-    fn skip_whitespace(&mut self) {
-        self.consume_until(|c| c != " " && c != "\t" && c != "\n" && c != "\r\n");
+        let span = self.span_from(start);
+        let caption = self.parse_caption("Listing:");
+        Ok(Some(MarkdownNode::Code(
+            lang, code, attributes, caption, span,
+        )))
     }
 
-    fn parse_header(&mut self) -> Option<MarkdownNode> {
-        let hashtags = self.consume_chars("#");
+    /// Parses what follows the `]` of a link or image with `text`, either an
+    /// inline `(url "title")` or a reference, followed by optional attributes.
+    /// Returns `None` when it is not a link.
+    fn parse_link_destination(&mut self, text: &str) -> Option<(String, Attributes)> {
+        let (url, title) = match self.parse_inline_destination() {
+            Some(x) => x,
+            None => self.parse_link_reference(text)?,
+        };
 
-        if !is_whitespace(self.peek(0)) {
-            self.go_back(hashtags.len());
-            return None;
+        // A title in the attributes replaces the quoted one
+        let mut attributes = self.parse_attributes().unwrap_or_default();
+        if let (Some(title), None) = (title, attributes.title()) {
+            attributes.values.insert(0, (String::from("title"), title));
         }
 
-        self.consume();
-        let header_name = String::from(self.consume_until(is_newline).trim());
-
-        Some(MarkdownNode::Header(header_name, hashtags.len()))
+        Some((url, attributes))
     }
 
-    fn parse_list(&mut self) -> Option<MarkdownNode> {
-        let mut nodes: Vec<MarkdownNode> = Vec::new();
-
-        if !is_whitespace(self.peek(1)) {
+    /// Parses a destination like `(url "title")` at the cursor, leaving the
+    /// cursor untouched when it isn't closed on the same line.
+    fn parse_inline_destination(&mut self) -> Option<(String, Option<String>)> {
+        let start = self.index;
+        if self.peek(0) != "(" {
             return None;
         }
+        self.consume();
 
-        while !self.eof() && (self.peek(0) == "*" || self.peek(0) == "-") {
-            self.consume();
-            nodes.push(self.parse_paragraph(true).expect("Failed to parse in list"));
-            self.skip_whitespace();
+        let url_start = self.index;
+        let mut url = self.consume_url(false);
+        self.consume_until(|c| !is_whitespace(c));
+        let title = self.parse_title();
+        self.consume_until(|c| !is_whitespace(c));
+
+        // Without a title, everything up to the parenthesis is the url
+        if title.is_none() && self.peek(0) != ")" {
+            self.index = url_start;
+            url = self.consume_url(true);
+        }
+
+        if self.peek(0) != ")" {
+            self.index = start;
+            return None;
         }
+        self.consume();
 
-        Some(MarkdownNode::List(nodes))
+        Some((url, title))
     }
 
-    fn parse_math(&mut self) -> Option<MarkdownNode> {
-        self.consume_chars("$");
+    /// Consumes a url up to the end of the line or a `)` that doesn't close a
+    /// parenthesis in the url itself, or whitespace unless `spaces` is set.
+    fn consume_url(&mut self, spaces: bool) -> String {
+        let start = self.index;
+        let mut depth = 0;
 
-        let math = String::from(self.consume_until(|c| c == "$").trim());
-        self.consume_until(|c| c != "$");
+        while let Some(c) = self.data.get(self.index) {
+            match *c {
+                "\\" if matches!(self.data.get(self.index + 1), Some(&"(") | Some(&")")) => {
+                    self.index += 1
+                }
+                "(" => depth += 1,
+                ")" if depth == 0 => break,
+                ")" => depth -= 1,
+                " " | "\t" if !spaces => break,
+                "\n" | "\r\n" => break,
+                _ => {}
+            }
+            self.index += 1;
+        }
 
-        Some(MarkdownNode::Math(math))
+        self.data[start..self.index].concat()
     }
 
-    fn parse_url(&mut self) -> ParagraphItem {
+    /// Parses a title quoted with `"`, `'` or parentheses, leaving the cursor
+    /// untouched when there is none.
+    fn parse_title(&mut self) -> Option<String> {
+        let start = self.index;
+        let quote = self.peek(0);
+        let close = match quote.as_str() {
+            "\"" | "'" => quote,
+            "(" => String::from(")"),
+            _ => return None,
+        };
         self.consume();
 
-        let url = self.consume_until(|c| c == ">");
-        let name = url.clone();
-
+        let mut title = String::default();
+        while self.peek(0) != close {
+            let c = self.peek(0);
+            if c.is_empty() || is_newline(c) {
+                self.index = start;
+                return None;
+            }
+            title.push_str(self.consume());
+        }
         self.consume();
 
-        ParagraphItem::Url(name, url)
+        Some(unescape(&title))
     }
 
-    fn parse_code(&mut self) -> Option<MarkdownNode> {
-        if self.consume_chars("`").len() != 3 {
+    /// Parses attributes like `{#id .class}` directly at the cursor, leaving
+    /// the cursor untouched when there are none.
+    fn parse_attributes(&mut self) -> Option<Attributes> {
+        if self.peek(0) != "{" {
             return None;
         }
 
-        let lang = if !is_newline(self.peek(0)) {
-            self.consume_until(is_newline).trim().to_lowercase()
-        } else {
-            String::default()
+        let start = self.index;
+        self.consume();
+        let text = self.consume_until(|c| c == "}" || is_newline(c));
+        let attributes = match self.peek(0).as_str() {
+            "}" => Attributes::parse(&text),
+            _ => None,
         };
 
-        let mut code = String::from(self.consume_until(|c| c == "`").trim());
-
-        if lang == "html" {
-            code = preprocess_html(code);
+        match attributes {
+            Some(_) => self.index += 1,
+            None => self.index = start,
         }
-        self.consume_chars("`");
+        attributes
+    }
 
-        Some(MarkdownNode::Code(lang, code))
+    /// Parses the opening `[` as text when a link or image turns out not to
+    /// be one, so the rest is parsed as regular inline content. The same is
+    /// done for unclosed code spans and inline math.
+    fn literal_bracket(&mut self, start: usize, length: usize) -> ParagraphItem {
+        self.index = start + length;
+        ParagraphItem::Text(self.data[start..self.index].concat(), self.span_from(start))
     }
 
-    fn parse_named_url(&mut self) -> ParagraphItem {
-        self.consume();
+    /// Returns the index of the `]` closing the `[` at `index` on the same
+    /// line, skipping nested brackets, escapes and code spans.
+    fn link_text_end(&mut self, index: usize) -> Option<usize> {
+        if !self.link_text_ends.contains_key(&index) {
+            self.match_brackets(index);
+        }
 
-        let name = self.consume_until(|c| c == "]");
+        *self.link_text_ends.entry(index).or_insert(None)
+    }
 
-        self.consume();
+    /// Matches all brackets on the line containing `index` in a single pass,
+    /// so the line isn't scanned again for every `[` on it.
+    fn match_brackets(&mut self, index: usize) {
+        let is_newline = |c: &&str| *c == "\n" || *c == "\r\n";
+        let line_start = self.data[..index]
+            .iter()
+            .rposition(is_newline)
+            .map_or(0, |i| i + 1);
+        let line_end = self.data[index..]
+            .iter()
+            .position(is_newline)
+            .map_or(self.data.len(), |i| index + i);
+
+        let mut open = vec![];
+        let mut i = line_start;
+        while i < line_end {
+            match self.data[i] {
+                "\\" if i + 1 < line_end => i += 1,
+                "`" => {
+                    let ticks = self.data[i..line_end]
+                        .iter()
+                        .take_while(|c| **c == "`")
+                        .count();
+                    let closing = (i + ticks..line_end)
+                        .find(|x| (*x..*x + ticks).all(|y| self.data.get(y) == Some(&"`")));
+
+                    // Without a closing run the whole run is text, shorter
+                    // runs inside it don't start code spans
+                    i = closing.unwrap_or(i) + ticks - 1;
+                }
+                "[" => open.push(i),
+                "]" => {
+                    if let Some(x) = open.pop() {
+                        self.link_text_ends.insert(x, Some(i));
+                    }
+                }
+                _ => {}
+            }
+            i += 1;
+        }
 
-        let mut url = String::default();
+        for x in open {
+            self.link_text_ends.insert(x, None);
+        }
+    }
 
-        if self.peek(0) == "(" {
-            self.consume();
-            url = self.consume_until(|c| c == ")");
+    /// Parses a link starting at `[`. A link can't contain other links, so
+    /// when its text does the brackets are kept as text around the parsed
+    /// content, which is why this can return several items.
+    fn parse_named_url(&mut self) -> Result<Vec<ParagraphItem>, ParseError> {
+        let start = self.index;
+        let end = match self.link_text_end(start) {
+            Some(x) if self.link_depth < MAX_LINK_DEPTH => x,
+            _ => return Ok(vec![self.literal_bracket(start, 1)]),
+        };
+        let name = self.data[start + 1..end].concat();
+        self.index = end + 1;
 
-            self.consume();
+        let (url, attributes) = match self.parse_link_destination(&name) {
+            Some(x) => x,
+            None => return Ok(vec![self.literal_bracket(start, 1)]),
+        };
+        let destination_end = self.index;
+
+        // The link text is parsed on its own, so emphasis can't cross it
+        let indices: Vec<usize> = (start + 1..end).collect();
+        let mut parser = self.sub_parser(&indices);
+        parser.link_depth += 1;
+        let children = parser.parse_inlines()?;
+
+        if contains_link(&children) {
+            let mut items = vec![self.literal_bracket(start, 1)];
+            items.extend(children);
+            self.index = end;
+            return Ok(items);
         }
 
-        ParagraphItem::Url(name, url)
+        self.index = destination_end;
+        Ok(vec![ParagraphItem::Url(
+            children,
+            url,
+            attributes,
+            self.span_from(start),
+        )])
     }
 
-    fn parse_image(&mut self) -> ParagraphItem {
-        self.consume();
-        self.consume();
+    fn parse_image(&mut self) -> Result<ParagraphItem, ParseError> {
+        let start = self.index;
+        let end = match self.link_text_end(start + 1) {
+            Some(x) => x,
+            None => return Ok(self.literal_bracket(start, 2)),
+        };
+        let alt_text = self.data[start + 2..end].concat();
+        self.index = end + 1;
 
-        let alt_text = self.consume_until(|c| c == "]");
+        let (url, attributes) = match self.parse_link_destination(&alt_text) {
+            Some(x) => x,
+            None => return Ok(self.literal_bracket(start, 2)),
+        };
 
-        self.consume();
-        self.consume();
+        Ok(ParagraphItem::Image(
+            url,
+            alt_text,
+            attributes,
+            self.span_from(start),
+        ))
+    }
 
-        let url = self.consume_until(|c| c == ")");
-        self.consume();
+    fn parse_paragraph(&mut self, single_line: bool) -> Result<MarkdownNode, ParseError> {
+        let start = self.index;
+        let children = self.parse_inlines()?;
 
-        ParagraphItem::Image(url, alt_text)
+        Ok(MarkdownNode::Paragraph(
+            children,
+            single_line,
+            self.span_from(start),
+        ))
     }
 
-    fn parse_paragraph(&mut self, single_line: bool) -> Option<MarkdownNode> {
-        let mut result: Vec<ParagraphItem> = Vec::new();
+    /// Parses inline content up to the end of the line.
+    fn parse_inlines(&mut self) -> Result<Vec<ParagraphItem>, ParseError> {
+        let mut inlines: Vec<Inline> = Vec::new();
 
         loop {
             if self.eof() {
@@ -385,163 +2587,499 @@ impl<'a> Parser<'a> {
                 break;
             }
 
-            let child = match curr.as_str() {
-                "*" => {
-                    let stars = self.consume_until(|c| c != "*").len();
-                    let text = self.consume_until(|c| c == "*");
-                    self.consume_chars("*");
+            let start = self.index;
 
-                    if stars == 1 {
-                        ParagraphItem::Italic(text)
-                    } else {
-                        ParagraphItem::Bold(text)
-                    }
-                }
-                "_" => {
-                    self.consume();
-                    let text = if single_line {
-                        self.consume_until(|c| c == "_" || is_newline(c))
+            let child = match curr.as_str() {
+                "*" | "_" | "~" | "=" | "^" => {
+                    let before = match start {
+                        0 => "",
+                        _ => self.data[start - 1],
+                    };
+                    let run = self.consume_chars(&curr);
+                    let after = self.peek(0);
+
+                    // The flanking rules from CommonMark decide if the run can
+                    // open or close emphasis
+                    let left = !is_flanking_space(&after)
+                        && (!is_punctuation(&after)
+                            || is_flanking_space(before)
+                            || is_punctuation(before));
+                    let right = !is_flanking_space(before)
+                        && (!is_punctuation(before)
+                            || is_flanking_space(&after)
+                            || is_punctuation(&after));
+
+                    let (can_open, can_close) = if curr == "_" {
+                        (
+                            left && (!right || is_punctuation(before)),
+                            right && (!left || is_punctuation(&after)),
+                        )
                     } else {
-                        self.consume_until(|c| c == "_")
+                        (left, right)
                     };
-                    self.consume();
 
-                    ParagraphItem::Italic(text)
+                    inlines.push(Inline::Delimiter(Delimiter {
+                        character: curr,
+                        start,
+                        count: run.len(),
+                        length: run.len(),
+                        can_open,
+                        can_close,
+                    }));
+                    continue;
                 }
                 "$" => {
+                    let dollars = if self.peek(1) == "$" { 2 } else { 1 };
+                    self.index += dollars;
+                    let text = self.consume_until(|c| c == "$" || is_newline(c));
+                    if !self.peek_str(&"$".repeat(dollars)) {
+                        self.literal_bracket(start, dollars)
+                    } else {
+                        self.index += dollars;
+                        ParagraphItem::InlineMath(text, self.span_from(start))
+                    }
+                }
+                "@" if self.peek_reference().is_some() => {
+                    let length = self.peek_reference().unwrap();
                     self.consume();
-                    let text = self.consume_until(|c| c == "$");
-                    self.consume();
-                    ParagraphItem::InlineMath(text)
+                    let label = self.data[self.index..start + length].concat();
+                    self.index = start + length;
+                    ParagraphItem::Reference(label, self.span_from(start))
+                }
+                "<" if self.is_autolink() => self.parse_url()?,
+                "<" if self.peek_html_tag().is_some() => self.parse_inline_html(),
+                "[" if self.peek(1) == "^" && self.peek_footnote_reference().is_some() => {
+                    let length = self.peek_footnote_reference().unwrap();
+                    let label = self.data[start + 2..start + length - 1].concat();
+                    self.index += length;
+                    ParagraphItem::FootnoteReference(label, self.span_from(start))
+                }
+                "[" => {
+                    let mut items = self.parse_named_url()?;
+                    let last = items.pop().unwrap();
+                    inlines.extend(items.into_iter().map(Inline::Item));
+                    last
                 }
-                "<" => self.parse_url(),
-                "[" => self.parse_named_url(),
-                "!" => self.parse_image(),
+                "!" if self.peek(1) == "[" => self.parse_image()?,
                 "`" => {
                     self.consume();
-                    let code = self.consume_until(|c| c == "`");
+                    let code = self.consume_until(|c| c == "`" || is_newline(c));
+                    if self.peek(0) != "`" {
+                        self.literal_bracket(start, 1)
+                    } else {
+                        self.consume();
+                        ParagraphItem::InlineCode(code, self.span_from(start))
+                    }
+                }
+                "\\" if is_escapable(self.peek(1)) => {
                     self.consume();
-                    ParagraphItem::InlineCode(code)
+                    let text = String::from(self.consume());
+                    ParagraphItem::Text(text, self.span_from(start))
                 }
                 _ => {
-                    let text = self.consume_until(|c| {
-                        c == "_"
+                    let mut text = String::from(self.consume());
+                    text += &self.consume_until(|c| {
+                        c == "\\"
+                            || c == "~"
+                            || c == "="
+                            || c == "^"
+                            || c == "_"
                             || c == "<"
                             || c == "*"
                             || c == "$"
                             || c == "["
+                            || c == "!"
                             || c == "`"
-                            || c == "|"
+                            || c == "@"
                             || is_newline(c)
                     });
                     //TODO: trim text here
-                    ParagraphItem::Text(text)
+                    ParagraphItem::Text(text, self.span_from(start))
                 }
             };
 
-            result.push(child);
+            inlines.push(Inline::Item(child));
+        }
+
+        Ok(self.process_emphasis(inlines))
+    }
 
-            //TODO: dirty fix this should be changed
-            if single_line && self.peek(0) == "|" {
-                break;
+    /// Turns matching delimiter runs into nested emphasis using the rules
+    /// from CommonMark, unmatched delimiters are kept as text. The same rules
+    /// are used for `~~strike~~`, `==highlight==`, `^sup^` and `~sub~`.
+    fn process_emphasis(&self, mut inlines: Vec<Inline>) -> Vec<ParagraphItem> {
+        let mut closer = 0;
+
+        // Index below which no opener matches closers of the same kind, so a
+        // failed search isn't repeated for the next closer like in CommonMark
+        let mut openers_bottom: HashMap<(String, usize, bool, bool), usize> = HashMap::new();
+
+        // Positions of whitespace, which superscript and subscript can't
+        // contain like in Pandoc
+        let delimiters = inlines.iter().filter_map(|x| match x {
+            Inline::Delimiter(x) => Some(x.start),
+            _ => None,
+        });
+        let spaces: Vec<usize> = match (delimiters.clone().min(), delimiters.max()) {
+            (Some(first), Some(last)) => (first..last)
+                .filter(|i| is_flanking_space(self.data[*i]))
+                .collect(),
+            _ => vec![],
+        };
+        let has_space = |start: usize, end: usize| {
+            let i = spaces.partition_point(|x| *x < start);
+            spaces.get(i).is_some_and(|x| *x < end)
+        };
+
+        while closer < inlines.len() {
+            let closing = match &inlines[closer] {
+                Inline::Delimiter(x) if x.can_close => x,
+                _ => {
+                    closer += 1;
+                    continue;
+                }
+            };
+
+            let kind = (
+                closing.character.clone(),
+                closing.length % 3,
+                closing.can_open,
+                closing.count >= 2,
+            );
+            let bottom = openers_bottom.get(&kind).copied().unwrap_or(0);
+
+            // Emphasis runs that can both open and close only match when
+            // their lengths don't add up to a multiple of three
+            let opener = (bottom..closer).rev().find(|i| match &inlines[*i] {
+                Inline::Delimiter(x) if x.character == closing.character && x.can_open => {
+                    match x.character.as_str() {
+                        "*" | "_" => {
+                            !((x.can_close || closing.can_open)
+                                && (x.length + closing.length) % 3 == 0
+                                && (x.length % 3 != 0 || closing.length % 3 != 0))
+                        }
+                        "=" => x.count >= 2 && closing.count >= 2,
+                        "~" if x.count >= 2 && closing.count >= 2 => true,
+                        _ => !has_space(x.start + x.count, closing.start),
+                    }
+                }
+                _ => false,
+            });
+            let opener = match opener {
+                Some(x) => x,
+                None => {
+                    openers_bottom.insert(kind, closer);
+                    closer += 1;
+                    continue;
+                }
+            };
+
+            // The inlines after the opener are replaced by the new item
+            for bottom in openers_bottom.values_mut() {
+                *bottom = (*bottom).min(opener + 1);
+            }
+
+            let children: Vec<Inline> = inlines.drain(opener + 1..closer).collect();
+            let children = self.inline_items(children);
+            closer = opener + 1;
+
+            let (opening, closing) = match &mut inlines[opener..=closer] {
+                [Inline::Delimiter(opening), Inline::Delimiter(closing)] => (opening, closing),
+                _ => unreachable!(),
+            };
+
+            let used = match opening.character.as_str() {
+                "=" => 2,
+                "^" => 1,
+                _ if opening.count >= 2 && closing.count >= 2 => 2,
+                _ => 1,
+            };
+            let character = opening.character.clone();
+            let span =
+                self.span_between(opening.start + opening.count - used, closing.start + used);
+            opening.count -= used;
+            closing.start += used;
+            closing.count -= used;
+            let (opening_empty, closing_empty) = (opening.count == 0, closing.count == 0);
+
+            let item = match (character.as_str(), used) {
+                ("~", 2) => ParagraphItem::Strikethrough(children, span),
+                ("~", _) => ParagraphItem::Subscript(children, span),
+                ("=", _) => ParagraphItem::Highlight(children, span),
+                ("^", _) => ParagraphItem::Superscript(children, span),
+                (_, 2) => ParagraphItem::Bold(children, span),
+                _ => ParagraphItem::Italic(children, span),
+            };
+            inlines.insert(closer, Inline::Item(item));
+            closer += 1;
+
+            if closing_empty {
+                inlines.remove(closer);
+            }
+            if opening_empty {
+                inlines.remove(opener);
+                closer -= 1;
+                for bottom in openers_bottom.values_mut() {
+                    *bottom = (*bottom).min(opener);
+                }
             }
         }
 
-        Some(MarkdownNode::Paragraph(result, single_line))
+        self.inline_items(inlines)
     }
 
-    fn parse_table(&mut self) -> Option<MarkdownNode> {
-        let mut headers: Vec<MarkdownNode> = vec![];
-
-        //TODO: replace all consume_chars("|") by assert_consume("|")
+    /// Converts inlines to paragraph items, joining neighbouring text.
+    fn inline_items(&self, inlines: Vec<Inline>) -> Vec<ParagraphItem> {
+        let mut result: Vec<ParagraphItem> = vec![];
+
+        for inline in inlines {
+            let item = match inline {
+                Inline::Item(x) => x,
+                Inline::Delimiter(x) if x.count == 0 => continue,
+                Inline::Delimiter(x) => ParagraphItem::Text(
+                    x.character.repeat(x.count),
+                    self.span_between(x.start, x.start + x.count),
+                ),
+            };
 
-        // Parse headers
-        self.consume_chars("|");
-        while !is_newline(self.peek(0)) {
-            headers.push(self.parse_paragraph(true).unwrap());
+            if let (Some(ParagraphItem::Text(text, span)), ParagraphItem::Text(next, next_span)) =
+                (result.last_mut(), &item)
+            {
+                text.push_str(next);
+                span.end = next_span.end;
+                continue;
+            }
 
-            self.consume_chars("|");
+            result.push(item);
         }
 
-        self.skip_whitespace();
+        result
+    }
 
-        // Parse horizontal line
-        self.consume_chars("|");
-        while !is_newline(self.peek(0)) {
-            self.consume_until(|c| c != "-" && !is_whitespace(c));
+    /// Splits the table row on the line starting at `index` into the indices
+    /// of its cells, without surrounding whitespace. An escaped `\|` is a
+    /// literal pipe, and pipes inside inline code don't end a cell.
+    fn table_cells(&self, index: usize) -> Vec<Vec<usize>> {
+        let end = self.line_end(index);
+        let is_space = |i: usize| is_whitespace(String::from(self.data[i]));
 
-            self.consume_chars("|");
+        let mut i = index;
+        while i < end && is_space(i) {
+            i += 1;
+        }
+        if i < end && self.data[i] == "|" {
+            i += 1;
         }
 
-        self.skip_whitespace();
+        let mut cells = vec![];
+        let mut cell = vec![];
+        while i < end {
+            match self.data[i] {
+                "\\" if i + 1 < end && self.data[i + 1] == "|" => {
+                    cell.push(i + 1);
+                    i += 2;
+                }
+                "`" => {
+                    let run = (i..end).take_while(|j| self.data[*j] == "`").count();
+
+                    // Find a closing run of the same length on this line
+                    let mut close = None;
+                    let mut j = i + run;
+                    while j < end {
+                        let length = (j..end).take_while(|k| self.data[*k] == "`").count();
+                        if length == run {
+                            close = Some(j + run);
+                            break;
+                        }
+                        j += length.max(1);
+                    }
+
+                    let code_end = close.unwrap_or(i + run);
+                    while i < code_end {
+                        if self.data[i] != "\\" || i + 1 == end || self.data[i + 1] != "|" {
+                            cell.push(i);
+                        }
+                        i += 1;
+                    }
+                }
+                "|" => {
+                    cells.push(cell);
+                    cell = vec![];
+                    i += 1;
+                }
+                _ => {
+                    cell.push(i);
+                    i += 1;
+                }
+            }
+        }
 
-        // Parse data
-        let mut data: Vec<MarkdownNode> = vec![];
+        // A trailing pipe does not start another cell
+        if cells.is_empty() || !cell.iter().all(|i| is_space(*i)) {
+            cells.push(cell);
+        }
+
+        for cell in cells.iter_mut() {
+            while cell.first().is_some_and(|i| is_space(*i)) {
+                cell.remove(0);
+            }
+            while cell.last().is_some_and(|i| is_space(*i)) {
+                cell.pop();
+            }
+        }
 
-        while !self.eof() && self.peek(0) == "|" {
-            self.consume_chars("|");
+        cells
+    }
 
-            while !is_newline(self.peek(0)) {
-                data.push(self.parse_paragraph(true).unwrap());
+    /// Reads the column alignments from the delimiter row starting at
+    /// `index`, returns `None` if the line is not a delimiter row.
+    fn table_alignments(&self, index: usize) -> Option<Vec<Alignment>> {
+        let mut alignments = vec![];
 
-                self.consume_chars("|");
+        for cell in self.table_cells(index) {
+            let text: String = cell.iter().map(|i| self.data[*i]).collect();
+            if !text.contains('-') || text.chars().any(|c| c != '-' && c != ':') {
+                return None;
             }
 
-            self.skip_whitespace();
+            alignments.push(match (text.starts_with(':'), text.ends_with(':')) {
+                (true, true) => Alignment::Center,
+                (true, false) => Alignment::Left,
+                (false, true) => Alignment::Right,
+                (false, false) => Alignment::None,
+            });
+        }
+
+        Some(alignments)
+    }
+
+    /// Parses the cells of the row starting at `index`. When `columns` is
+    /// given, missing cells are added as empty ones and extra cells dropped.
+    fn parse_table_row(
+        &self,
+        index: usize,
+        columns: Option<usize>,
+    ) -> Result<Vec<MarkdownNode>, ParseError> {
+        let mut cells = self.table_cells(index);
+        if let Some(columns) = columns {
+            cells.resize(columns, vec![]);
+        }
+
+        cells
+            .iter()
+            .map(|cell| self.sub_parser(cell).parse_paragraph(true))
+            .collect()
+    }
+
+    fn parse_table(&mut self) -> Result<Option<MarkdownNode>, ParseError> {
+        let start = self.index;
+
+        let delimiter_row = self.line_end(start) + 1;
+        if delimiter_row >= self.data.len() {
+            return Ok(None);
         }
+        let mut alignments = match self.table_alignments(delimiter_row) {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+
+        let headers = self.parse_table_row(start, None)?;
+        alignments.resize(headers.len(), Alignment::None);
+        self.index = self.line_end(delimiter_row);
+
+        // Rows continue until a line that does not start with a pipe
+        let mut rows = vec![];
+        let mut end = self.index;
+
+        while self.index + 1 < self.data.len() && self.data[self.index + 1] == "|" {
+            let row = self.index + 1;
+            rows.push(self.parse_table_row(row, Some(headers.len()))?);
 
-        Some(MarkdownNode::Table(headers, data))
+            self.index = self.line_end(row);
+            end = self.index;
+        }
+
+        let span = self.span_between(start, end);
+        let caption = self.parse_caption("Table:");
+        Ok(Some(MarkdownNode::Table(
+            headers, rows, alignments, caption, span,
+        )))
     }
 
-    pub fn next_node(&mut self, single_line: bool) -> Option<MarkdownNode> {
+    pub fn next_node(&mut self, single_line: bool) -> Result<Option<MarkdownNode>, ParseError> {
         self.skip_whitespace();
 
+        // Footnote and link definitions are collected instead of returned as
+        // nodes, the links already were by `link_definitions`
+        loop {
+            if self.peek_str("[^") {
+                match self.parse_footnote() {
+                    Ok(Some(x)) => self.footnotes.push(x),
+                    Ok(None) => break,
+                    Err(e) => {
+                        self.index = self.data.len();
+                        return Err(e);
+                    }
+                }
+            } else if self.parse_link_definition().is_none() {
+                break;
+            }
+
+            self.skip_whitespace();
+        }
+
         if self.eof() {
-            return None;
+            return Ok(None);
         }
 
         let current_char = self.peek(0);
-        let result_node: Option<MarkdownNode> = match current_char.as_str() {
+        let result_node = match current_char.as_str() {
             "#" => self.parse_header(),
-            "$" => self.parse_math(),
+            "$" if self.peek_str("$$") => self.parse_math(),
+            "\\" if self.peek_str("\\begin{") => self.parse_math_environment(),
             "`" => self.parse_code(),
-            "-" => self.parse_list(),
             "|" => self.parse_table(),
-            "@" => {
+            ">" => self.parse_block_quote(),
+            "!" if self.peek(1) == "[" => self.parse_figure(),
+            "@" if self.peek_reference().is_none() => {
+                let start = self.index;
                 self.consume();
-                Some(MarkdownNode::PageBreak())
-            }
-            _ => {
-                if current_char == "*" && self.peek(1) != "*" {
-                    return self.parse_list();
-                }
-                self.parse_paragraph(single_line)
+                Ok(Some(MarkdownNode::PageBreak(self.span_from(start))))
             }
+            _ if self.peek_list_marker().is_some() => self.parse_list(),
+            _ => Ok(None),
         };
 
-        result_node
-    }
+        let result_node = match result_node {
+            Ok(None) => self.parse_paragraph(single_line).map(Some),
+            x => x,
+        };
 
-    pub fn get_html(&mut self, base_path: &Path, fast: bool) -> String {
-        let mut result = String::new();
+        // Stop at the first error instead of reporting the same problem again
+        if result_node.is_err() {
+            self.index = self.data.len();
+        }
 
-        loop {
-            let node = self.next_node(false);
+        result_node
+    }
 
-            match node {
-                Some(x) => result.push_str(x.to_html(base_path, fast).as_str()),
-                None => break,
-            }
-        }
+    pub fn get_html(&mut self, renderer: &Renderer) -> Result<String, ParseError> {
+        let nodes = self.collect::<Result<Vec<_>, _>>()?;
+        let footnotes = self.take_footnotes();
+        renderer.add_document_labels(&nodes, &footnotes);
+        renderer.render_document(&nodes, &footnotes)
+    }
 
-        result
+    /// Returns the footnote definitions parsed since the last call.
+    pub fn take_footnotes(&mut self) -> Vec<Footnote> {
+        std::mem::take(&mut self.footnotes)
     }
 }
 
 impl Iterator for Parser<'_> {
-    type Item = MarkdownNode;
+    type Item = Result<MarkdownNode, ParseError>;
 
-    fn next(&mut self) -> Option<MarkdownNode> {
-        self.next_node(false)
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_node(false).transpose()
     }
 }
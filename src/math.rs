@@ -0,0 +1,1043 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::process::Command;
+use std::rc::Rc;
+use std::sync::OnceLock;
+
+/// How math is written to the HTML output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MathOutput {
+    /// Keep the TeX source between `$` signs and let MathJax render it.
+    MathJax,
+    /// Render to SVG with the external `tex2svg` tool.
+    Svg,
+    /// Convert to MathML without any external tools.
+    MathMl,
+}
+
+/// Bump when the output of `tex_to_mathml` changes, so cached MathML from
+/// an older converter is not used anymore.
+const MATHML_REVISION: u32 = 1;
+
+impl MathOutput {
+    /// Identifies the converter used for this output, which is part of the
+    /// cache key so updating Rabbit or `tex2svg` renders math again.
+    pub fn converter(&self) -> &'static str {
+        static MATHML: OnceLock<String> = OnceLock::new();
+        static SVG: OnceLock<String> = OnceLock::new();
+
+        match self {
+            MathOutput::MathJax => "mathjax",
+            MathOutput::MathMl => MATHML.get_or_init(|| {
+                format!(
+                    "rabbit {} mathml {}",
+                    env!("CARGO_PKG_VERSION"),
+                    MATHML_REVISION
+                )
+            }),
+            MathOutput::Svg => SVG.get_or_init(|| {
+                let version = Command::new("tex2svg")
+                    .arg("--version")
+                    .output()
+                    .ok()
+                    .and_then(|x| String::from_utf8(x.stdout).ok())
+                    .unwrap_or_default();
+                format!("tex2svg {}", version.trim())
+            }),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MathError {
+    pub message: String,
+    /// Character offset of the problem in the TeX source, if known.
+    pub offset: Option<usize>,
+}
+
+impl fmt::Display for MathError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.offset {
+            Some(offset) => write!(f, "{} at offset {}", self.message, offset),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+impl Error for MathError {}
+
+/// Renders math with `tex2svg`. Fails when the tool cannot be run, exits
+/// with an error or complains on stderr.
+pub fn tex_to_svg(input: &str, inline: bool) -> Result<String, MathError> {
+    let error = |message| MathError {
+        message,
+        offset: None,
+    };
+
+    let mut command = Command::new("tex2svg");
+    command.arg(input);
+
+    if inline {
+        command.arg("--inline");
+    }
+
+    let output = command
+        .output()
+        .map_err(|e| error(format!("failed to run `tex2svg`: {}", e)))?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let stderr = stderr.trim();
+
+    if !output.status.success() {
+        return Err(error(if stderr.is_empty() {
+            format!("`tex2svg` failed with {}", output.status)
+        } else {
+            format!("`tex2svg` failed with {}: {}", output.status, stderr)
+        }));
+    }
+    if !stderr.is_empty() {
+        return Err(error(format!("`tex2svg` reported: {}", stderr)));
+    }
+
+    String::from_utf8(output.stdout)
+        .map_err(|_| error(String::from("`tex2svg` produced invalid UTF-8")))
+}
+
+/// Rendered math keyed by its source, so every formula is only converted
+/// once. Clones share the same entries, which lets a cache live across
+/// recompiles in watch mode.
+#[derive(Debug, Clone, Default)]
+pub struct MathCache {
+    entries: Rc<RefCell<HashMap<String, String>>>,
+    dir: Option<PathBuf>,
+}
+
+impl MathCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Also stores entries as files in `dir`, so they survive between runs.
+    pub fn with_dir(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+
+        Ok(MathCache {
+            entries: Rc::default(),
+            dir: Some(dir),
+        })
+    }
+
+    /// Returns the entry for `key` from memory or disk.
+    pub fn get(&self, key: &str) -> Option<String> {
+        if let Some(x) = self.entries.borrow().get(key) {
+            return Some(x.clone());
+        }
+
+        // Files are named by a hash of the key, they start with the full key
+        // to rule out collisions
+        let file = fs::read_to_string(self.path(key)?).ok()?;
+        let (stored, value) = file.split_once('\0')?;
+        if stored != key {
+            return None;
+        }
+
+        self.entries
+            .borrow_mut()
+            .insert(String::from(key), String::from(value));
+        Some(String::from(value))
+    }
+
+    /// Adds an entry, the error of writing it to disk is returned but the
+    /// entry is kept in memory regardless.
+    pub fn insert(&self, key: &str, value: String) -> io::Result<()> {
+        let file = self.path(key).map(|x| (x, format!("{}\0{}", key, value)));
+        self.entries.borrow_mut().insert(String::from(key), value);
+
+        match file {
+            Some((path, content)) => fs::write(path, content),
+            None => Ok(()),
+        }
+    }
+
+    fn path(&self, key: &str) -> Option<PathBuf> {
+        self.dir
+            .as_ref()
+            .map(|x| x.join(format!("{:016x}", fnv_hash(key))))
+    }
+}
+
+/// 64-bit FNV-1a, which unlike the std hasher is stable between builds.
+fn fnv_hash(key: &str) -> u64 {
+    key.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// Converts TeX math to a MathML `<math>` element.
+pub fn tex_to_mathml(input: &str, display: bool) -> Result<String, MathError> {
+    let mut parser = MathParser::new(input);
+    let content = parser.parse_rows(None)?;
+
+    Ok(format!(
+        "<math xmlns=\"http://www.w3.org/1998/Math/MathML\" display=\"{}\">{}</math>",
+        if display { "block" } else { "inline" },
+        content
+    ))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Command(String),
+    Number(String),
+    Letter(char),
+    Symbol(char),
+    Open,
+    Close,
+    Superscript,
+    Subscript,
+    Ampersand,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Limits {
+    /// Scripts are placed to the side of the base.
+    Side,
+    /// Scripts go above and below in display math, to the side inline.
+    Movable,
+    /// Scripts are always placed above and below.
+    Always,
+}
+
+struct Atom {
+    html: String,
+    limits: Limits,
+}
+
+impl Atom {
+    fn new(html: String) -> Self {
+        Atom {
+            html,
+            limits: Limits::Side,
+        }
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn mrow(items: Vec<String>) -> String {
+    if items.len() == 1 {
+        items.into_iter().next().unwrap()
+    } else {
+        format!("<mrow>{}</mrow>", items.concat())
+    }
+}
+
+fn greek_letter(name: &str) -> Option<&'static str> {
+    Some(match name {
+        "alpha" => "α",
+        "beta" => "β",
+        "gamma" => "γ",
+        "delta" => "δ",
+        "epsilon" => "ϵ",
+        "varepsilon" => "ε",
+        "zeta" => "ζ",
+        "eta" => "η",
+        "theta" => "θ",
+        "vartheta" => "ϑ",
+        "iota" => "ι",
+        "kappa" => "κ",
+        "lambda" => "λ",
+        "mu" => "μ",
+        "nu" => "ν",
+        "xi" => "ξ",
+        "pi" => "π",
+        "varpi" => "ϖ",
+        "rho" => "ρ",
+        "varrho" => "ϱ",
+        "sigma" => "σ",
+        "varsigma" => "ς",
+        "tau" => "τ",
+        "upsilon" => "υ",
+        "phi" => "ϕ",
+        "varphi" => "φ",
+        "chi" => "χ",
+        "psi" => "ψ",
+        "omega" => "ω",
+        _ => return None,
+    })
+}
+
+fn upper_greek_letter(name: &str) -> Option<&'static str> {
+    Some(match name {
+        "Gamma" => "Γ",
+        "Delta" => "Δ",
+        "Theta" => "Θ",
+        "Lambda" => "Λ",
+        "Xi" => "Ξ",
+        "Pi" => "Π",
+        "Sigma" => "Σ",
+        "Upsilon" => "Υ",
+        "Phi" => "Φ",
+        "Psi" => "Ψ",
+        "Omega" => "Ω",
+        _ => return None,
+    })
+}
+
+fn identifier(name: &str) -> Option<&'static str> {
+    Some(match name {
+        "infty" => "∞",
+        "partial" => "∂",
+        "nabla" => "∇",
+        "emptyset" | "varnothing" => "∅",
+        "ell" => "ℓ",
+        "hbar" => "ℏ",
+        "Re" => "ℜ",
+        "Im" => "ℑ",
+        "aleph" => "ℵ",
+        "imath" => "ı",
+        "jmath" => "ȷ",
+        _ => return None,
+    })
+}
+
+fn operator(name: &str) -> Option<&'static str> {
+    Some(match name {
+        "pm" => "±",
+        "mp" => "∓",
+        "times" => "×",
+        "div" => "÷",
+        "cdot" => "⋅",
+        "ast" => "∗",
+        "star" => "⋆",
+        "circ" => "∘",
+        "bullet" => "∙",
+        "oplus" => "⊕",
+        "ominus" => "⊖",
+        "otimes" => "⊗",
+        "odot" => "⊙",
+        "leq" | "le" => "≤",
+        "geq" | "ge" => "≥",
+        "neq" | "ne" => "≠",
+        "approx" => "≈",
+        "equiv" => "≡",
+        "sim" => "∼",
+        "simeq" => "≃",
+        "cong" => "≅",
+        "propto" => "∝",
+        "ll" => "≪",
+        "gg" => "≫",
+        "subset" => "⊂",
+        "supset" => "⊃",
+        "subseteq" => "⊆",
+        "supseteq" => "⊇",
+        "in" => "∈",
+        "notin" => "∉",
+        "ni" => "∋",
+        "cup" => "∪",
+        "cap" => "∩",
+        "setminus" => "∖",
+        "wedge" | "land" => "∧",
+        "vee" | "lor" => "∨",
+        "neg" | "lnot" => "¬",
+        "forall" => "∀",
+        "exists" => "∃",
+        "to" | "rightarrow" => "→",
+        "leftarrow" | "gets" => "←",
+        "Rightarrow" => "⇒",
+        "Leftarrow" => "⇐",
+        "leftrightarrow" => "↔",
+        "Leftrightarrow" => "⇔",
+        "implies" => "⟹",
+        "iff" => "⟺",
+        "mapsto" => "↦",
+        "uparrow" => "↑",
+        "downarrow" => "↓",
+        "ldots" | "dots" => "…",
+        "cdots" => "⋯",
+        "vdots" => "⋮",
+        "ddots" => "⋱",
+        "angle" => "∠",
+        "perp" => "⊥",
+        "parallel" => "∥",
+        "mid" => "∣",
+        "langle" => "⟨",
+        "rangle" => "⟩",
+        "lfloor" => "⌊",
+        "rfloor" => "⌋",
+        "lceil" => "⌈",
+        "rceil" => "⌉",
+        "vert" => "|",
+        "Vert" => "‖",
+        "prime" => "′",
+        "{" => "{",
+        "}" => "}",
+        "|" => "‖",
+        "%" => "%",
+        "$" => "$",
+        "#" => "#",
+        "&" => "&",
+        "_" => "_",
+        _ => return None,
+    })
+}
+
+fn big_operator(name: &str) -> Option<(&'static str, Limits)> {
+    Some(match name {
+        "sum" => ("∑", Limits::Movable),
+        "prod" => ("∏", Limits::Movable),
+        "coprod" => ("∐", Limits::Movable),
+        "bigcup" => ("⋃", Limits::Movable),
+        "bigcap" => ("⋂", Limits::Movable),
+        "bigoplus" => ("⨁", Limits::Movable),
+        "bigotimes" => ("⨂", Limits::Movable),
+        "int" => ("∫", Limits::Side),
+        "iint" => ("∬", Limits::Side),
+        "iiint" => ("∭", Limits::Side),
+        "oint" => ("∮", Limits::Side),
+        _ => return None,
+    })
+}
+
+fn function(name: &str) -> Option<Limits> {
+    Some(match name {
+        "sin" | "cos" | "tan" | "cot" | "sec" | "csc" | "arcsin" | "arccos" | "arctan" | "sinh"
+        | "cosh" | "tanh" | "coth" | "log" | "ln" | "lg" | "exp" | "dim" | "ker" | "deg"
+        | "arg" | "hom" => Limits::Side,
+        "lim" | "limsup" | "liminf" | "max" | "min" | "sup" | "inf" | "det" | "gcd" | "Pr" => {
+            Limits::Movable
+        }
+        _ => return None,
+    })
+}
+
+fn space(name: &str) -> Option<&'static str> {
+    Some(match name {
+        "," | "thinspace" => "0.1667em",
+        ":" | ">" | "medspace" => "0.2222em",
+        ";" | "thickspace" => "0.2778em",
+        "!" | "negthinspace" => "-0.1667em",
+        " " => "0.25em",
+        "quad" => "1em",
+        "qquad" => "2em",
+        _ => return None,
+    })
+}
+
+fn accent(name: &str) -> Option<(&'static str, bool)> {
+    Some(match name {
+        "hat" => ("^", true),
+        "widehat" => ("^", true),
+        "bar" => ("¯", true),
+        "overline" => ("‾", false),
+        "vec" => ("→", true),
+        "overrightarrow" => ("→", false),
+        "tilde" => ("~", true),
+        "widetilde" => ("~", true),
+        "dot" => ("˙", true),
+        "ddot" => ("¨", true),
+        "check" => ("ˇ", true),
+        "breve" => ("˘", true),
+        "acute" => ("´", true),
+        "grave" => ("`", true),
+        "overbrace" => ("⏞", false),
+        _ => return None,
+    })
+}
+
+fn font(name: &str) -> Option<&'static str> {
+    Some(match name {
+        "mathrm" | "rm" => "normal",
+        "mathbf" | "bf" | "boldsymbol" => "bold",
+        "mathit" | "it" => "italic",
+        "mathbb" => "double-struck",
+        "mathcal" => "script",
+        "mathfrak" => "fraktur",
+        "mathsf" => "sans-serif",
+        "mathtt" => "monospace",
+        _ => return None,
+    })
+}
+
+fn environment_delimiters(name: &str) -> Option<(&'static str, &'static str)> {
+    Some(match name {
+        "matrix" | "array" | "align" | "align*" | "aligned" | "gather" | "gather*" | "gathered"
+        | "equation" | "equation*" | "split" | "eqnarray" | "eqnarray*" => ("", ""),
+        "pmatrix" => ("(", ")"),
+        "bmatrix" => ("[", "]"),
+        "Bmatrix" => ("{", "}"),
+        "vmatrix" => ("|", "|"),
+        "Vmatrix" => ("‖", "‖"),
+        "cases" => ("{", ""),
+        _ => return None,
+    })
+}
+
+fn tokenize(input: &[char]) -> Vec<(Token, usize)> {
+    let mut tokens = vec![];
+    let mut index = 0;
+
+    while index < input.len() {
+        let start = index;
+        let c = input[index];
+        index += 1;
+
+        let token = match c {
+            '\\' => {
+                let mut name = String::default();
+                while index < input.len() && input[index].is_ascii_alphabetic() {
+                    name.push(input[index]);
+                    index += 1;
+                }
+                if name.is_empty() && index < input.len() {
+                    name.push(input[index]);
+                    index += 1;
+                }
+                Token::Command(name)
+            }
+            '{' => Token::Open,
+            '}' => Token::Close,
+            '^' => Token::Superscript,
+            '_' => Token::Subscript,
+            '&' => Token::Ampersand,
+            '%' => {
+                while index < input.len() && input[index] != '\n' {
+                    index += 1;
+                }
+                continue;
+            }
+            c if c.is_whitespace() => continue,
+            c if c.is_ascii_digit() => {
+                let mut number = c.to_string();
+                while index < input.len()
+                    && (input[index].is_ascii_digit()
+                        || (input[index] == '.'
+                            && index + 1 < input.len()
+                            && input[index + 1].is_ascii_digit()))
+                {
+                    number.push(input[index]);
+                    index += 1;
+                }
+                Token::Number(number)
+            }
+            c if c.is_alphabetic() => Token::Letter(c),
+            c => Token::Symbol(c),
+        };
+
+        tokens.push((token, start));
+    }
+
+    tokens
+}
+
+/// Groups, commands and environments nested deeper than this are an error,
+/// which bounds the recursion on input like `{{{{...`.
+const MAX_DEPTH: usize = 128;
+
+struct MathParser {
+    input: Vec<char>,
+    tokens: Vec<(Token, usize)>,
+    index: usize,
+    /// Number of bases that are being parsed.
+    depth: usize,
+}
+
+impl MathParser {
+    fn new(input: &str) -> Self {
+        let input: Vec<char> = input.chars().collect();
+
+        MathParser {
+            tokens: tokenize(&input),
+            input,
+            index: 0,
+            depth: 0,
+        }
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.index).map(|(token, _)| token)
+    }
+
+    fn offset(&self) -> usize {
+        match self.tokens.get(self.index) {
+            Some((_, offset)) => *offset,
+            None => self.input.len(),
+        }
+    }
+
+    fn error(&self, message: impl ToString) -> MathError {
+        MathError {
+            message: message.to_string(),
+            offset: Some(self.offset()),
+        }
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.index).map(|(token, _)| token.clone());
+        self.index += 1;
+        token
+    }
+
+    fn expect(&mut self, expected: Token, description: &str) -> Result<(), MathError> {
+        if self.peek() != Some(&expected) {
+            return Err(self.error(format!("expected {}", description)));
+        }
+
+        self.index += 1;
+        Ok(())
+    }
+
+    fn at_row_end(&self) -> bool {
+        match self.peek() {
+            None | Some(Token::Close) | Some(Token::Ampersand) => true,
+            Some(Token::Command(name)) => name == "\\" || name == "end" || name == "right",
+            _ => false,
+        }
+    }
+
+    /// Parses a row of atoms up to the end of the current group or cell.
+    fn parse_row(&mut self) -> Result<String, MathError> {
+        let mut items = vec![];
+
+        while !self.at_row_end() {
+            items.push(self.parse_atom()?);
+        }
+
+        Ok(mrow(items))
+    }
+
+    /// Parses cells separated by `&` and rows separated by `\\` into a table,
+    /// or into a plain row if there is only a single cell.
+    fn parse_rows(&mut self, environment: Option<&str>) -> Result<String, MathError> {
+        let mut rows = vec![];
+        let mut cells = vec![];
+
+        loop {
+            cells.push(self.parse_row()?);
+
+            match self.peek() {
+                Some(Token::Ampersand) => {
+                    self.index += 1;
+                }
+                Some(Token::Command(name)) if name == "\\" => {
+                    self.index += 1;
+                    rows.push(cells);
+                    cells = vec![];
+                }
+                Some(Token::Command(name)) if name == "end" && environment.is_some() => break,
+                None if environment.is_none() => break,
+                Some(Token::Close) => return Err(self.error("unmatched `}`")),
+                Some(Token::Command(name)) if name == "right" => {
+                    return Err(self.error("`\\right` without matching `\\left`"))
+                }
+                Some(Token::Command(name)) if name == "end" => {
+                    return Err(self.error("`\\end` without matching `\\begin`"))
+                }
+                _ => return Err(self.error("unexpected end of math")),
+            }
+        }
+
+        // A trailing `\\` does not start a new row
+        if cells.len() > 1 || !cells[0].is_empty() || rows.is_empty() {
+            rows.push(cells);
+        }
+
+        if rows.len() == 1 && rows[0].len() == 1 && environment.is_none() {
+            return Ok(rows.remove(0).remove(0));
+        }
+
+        let aligned = matches!(
+            environment,
+            Some("align") | Some("align*") | Some("aligned") | Some("split") | Some("eqnarray")
+        );
+        let mut table = String::from(if aligned {
+            "<mtable columnalign=\"right left right left right left\" columnspacing=\"0em 2em\">"
+        } else if environment == Some("cases") {
+            "<mtable columnalign=\"left left\">"
+        } else {
+            "<mtable>"
+        });
+        for row in rows {
+            table.push_str("<mtr>");
+            for cell in row {
+                table.push_str(&format!("<mtd>{}</mtd>", cell));
+            }
+            table.push_str("</mtr>");
+        }
+        table.push_str("</mtable>");
+
+        Ok(table)
+    }
+
+    /// Parses a base with its optional sub- and superscripts.
+    fn parse_atom(&mut self) -> Result<String, MathError> {
+        let mut base = self.parse_base()?;
+        let mut subscript = None;
+        let mut superscript = None;
+
+        loop {
+            match self.peek() {
+                Some(Token::Command(name)) if name == "limits" => {
+                    self.index += 1;
+                    base.limits = Limits::Always;
+                }
+                Some(Token::Command(name)) if name == "nolimits" => {
+                    self.index += 1;
+                    base.limits = Limits::Side;
+                }
+                Some(Token::Subscript) => {
+                    if subscript.is_some() {
+                        return Err(self.error("double subscript"));
+                    }
+                    self.index += 1;
+                    subscript = Some(self.parse_argument()?);
+                }
+                Some(Token::Superscript) => {
+                    if superscript.is_some() {
+                        return Err(self.error("double superscript"));
+                    }
+                    self.index += 1;
+                    superscript = Some(self.parse_argument()?);
+                }
+                Some(Token::Symbol('\'')) => {
+                    self.index += 1;
+                    let prime = String::from("<mo>′</mo>");
+                    superscript = Some(match superscript {
+                        Some(x) => mrow(vec![prime, x]),
+                        None => prime,
+                    });
+                }
+                _ => break,
+            }
+        }
+
+        let html = base.html;
+        let under = base.limits != Limits::Side;
+        Ok(match (subscript, superscript) {
+            (None, None) => html,
+            (Some(sub), None) if under => format!("<munder>{}{}</munder>", html, sub),
+            (Some(sub), None) => format!("<msub>{}{}</msub>", html, sub),
+            (None, Some(sup)) if under => format!("<mover>{}{}</mover>", html, sup),
+            (None, Some(sup)) => format!("<msup>{}{}</msup>", html, sup),
+            (Some(sub), Some(sup)) if under => {
+                format!("<munderover>{}{}{}</munderover>", html, sub, sup)
+            }
+            (Some(sub), Some(sup)) => format!("<msubsup>{}{}{}</msubsup>", html, sub, sup),
+        })
+    }
+
+    /// Parses the argument of a command or script, either a group in braces
+    /// or a single token.
+    fn parse_argument(&mut self) -> Result<String, MathError> {
+        match self.peek() {
+            Some(Token::Number(number)) if number.chars().count() > 1 => {
+                // `x^12` only raises the first digit
+                let mut digits = number.chars();
+                let first = digits.next().unwrap();
+                self.tokens[self.index].0 = Token::Number(digits.collect());
+                self.tokens[self.index].1 += 1;
+                Ok(format!("<mn>{}</mn>", first))
+            }
+            None => Err(self.error("missing argument")),
+            _ if self.at_row_end() && self.peek() != Some(&Token::Open) => {
+                Err(self.error("missing argument"))
+            }
+            _ => Ok(self.parse_base()?.html),
+        }
+    }
+
+    /// Reads the raw source of a group in braces, used for text.
+    fn parse_raw_group(&mut self) -> Result<String, MathError> {
+        if self.peek() != Some(&Token::Open) {
+            return Err(self.error("expected `{`"));
+        }
+
+        let start = self.offset() + 1;
+        let mut depth = 0;
+        let mut end = start;
+        for (i, c) in self.input.iter().enumerate().skip(start) {
+            match c {
+                '{' => depth += 1,
+                '}' if depth == 0 => {
+                    end = i;
+                    break;
+                }
+                '}' => depth -= 1,
+                _ => {}
+            }
+            end = i + 1;
+        }
+        if end >= self.input.len() {
+            return Err(self.error("unmatched `{`"));
+        }
+
+        while self.index < self.tokens.len() && self.tokens[self.index].1 <= end {
+            self.index += 1;
+        }
+
+        Ok(self.input[start..end].iter().collect())
+    }
+
+    fn parse_delimiter(&mut self) -> Result<String, MathError> {
+        let delimiter = match self.next() {
+            Some(Token::Symbol('.')) => String::default(),
+            Some(Token::Symbol(c)) => c.to_string(),
+            Some(Token::Command(name)) => match operator(&name) {
+                Some(x) => String::from(x),
+                None => {
+                    self.index -= 1;
+                    return Err(self.error(format!("invalid delimiter `\\{}`", name)));
+                }
+            },
+            _ => {
+                self.index -= 1;
+                return Err(self.error("missing delimiter"));
+            }
+        };
+
+        Ok(format!(
+            "<mo fence=\"true\" stretchy=\"true\">{}</mo>",
+            escape(&delimiter)
+        ))
+    }
+
+    /// Parses a single token, group or command. Every nested group passes
+    /// through here, so this is where the nesting depth is limited.
+    fn parse_base(&mut self) -> Result<Atom, MathError> {
+        if self.depth >= MAX_DEPTH {
+            return Err(self.error("math nested too deeply"));
+        }
+
+        self.depth += 1;
+        let result = self.parse_token();
+        self.depth -= 1;
+        result
+    }
+
+    fn parse_token(&mut self) -> Result<Atom, MathError> {
+        let token = match self.next() {
+            Some(x) => x,
+            None => return Err(self.error("unexpected end of math")),
+        };
+
+        Ok(Atom::new(match token {
+            Token::Number(number) => format!("<mn>{}</mn>", number),
+            Token::Letter(c) => format!("<mi>{}</mi>", c),
+            Token::Symbol(c) => {
+                let c = match c {
+                    '-' => '−',
+                    '*' => '∗',
+                    '\'' => '′',
+                    c => c,
+                };
+                format!("<mo>{}</mo>", escape(&c.to_string()))
+            }
+            Token::Open => {
+                let row = self.parse_rows_in_group()?;
+                self.expect(Token::Close, "`}`")?;
+                format!("<mrow>{}</mrow>", row)
+            }
+            Token::Close => {
+                self.index -= 1;
+                return Err(self.error("unmatched `}`"));
+            }
+            Token::Superscript | Token::Subscript => {
+                // A script without a base, like `^2` at the start of a group
+                self.index -= 1;
+                String::from("<mrow></mrow>")
+            }
+            Token::Ampersand => {
+                self.index -= 1;
+                return Err(self.error("unexpected `&`"));
+            }
+            Token::Command(name) => return self.parse_command(name),
+        }))
+    }
+
+    fn parse_rows_in_group(&mut self) -> Result<String, MathError> {
+        let mut items = vec![];
+
+        while self.peek().is_some() && self.peek() != Some(&Token::Close) {
+            if self.at_row_end() {
+                return Err(self.error("unexpected token in group"));
+            }
+            items.push(self.parse_atom()?);
+        }
+
+        Ok(items.concat())
+    }
+
+    fn parse_command(&mut self, name: String) -> Result<Atom, MathError> {
+        let command_offset = self.tokens[self.index - 1].1;
+
+        if let Some(x) = greek_letter(&name) {
+            return Ok(Atom::new(format!("<mi>{}</mi>", x)));
+        }
+        if let Some(x) = upper_greek_letter(&name) {
+            return Ok(Atom::new(format!("<mi mathvariant=\"normal\">{}</mi>", x)));
+        }
+        if let Some(x) = identifier(&name) {
+            return Ok(Atom::new(format!("<mi>{}</mi>", x)));
+        }
+        if let Some(x) = operator(&name) {
+            return Ok(Atom::new(format!("<mo>{}</mo>", escape(x))));
+        }
+        if let Some((x, limits)) = big_operator(&name) {
+            let movable = if limits == Limits::Movable {
+                " movablelimits=\"true\""
+            } else {
+                ""
+            };
+            return Ok(Atom {
+                html: format!("<mo largeop=\"true\"{}>{}</mo>", movable, x),
+                limits,
+            });
+        }
+        if let Some(limits) = function(&name) {
+            let movable = if limits == Limits::Movable {
+                " movablelimits=\"true\""
+            } else {
+                ""
+            };
+            return Ok(Atom {
+                html: format!("<mo{}>{}</mo>", movable, name),
+                limits,
+            });
+        }
+        if let Some(width) = space(&name) {
+            return Ok(Atom::new(format!("<mspace width=\"{}\"/>", width)));
+        }
+        if let Some((symbol, is_accent)) = accent(&name) {
+            let argument = self.parse_argument()?;
+            return Ok(Atom::new(format!(
+                "<mover accent=\"{}\">{}<mo stretchy=\"{}\">{}</mo></mover>",
+                is_accent, argument, !is_accent, symbol
+            )));
+        }
+        if let Some(variant) = font(&name) {
+            let argument = self.parse_argument()?;
+            return Ok(Atom::new(
+                argument
+                    .replace("<mi>", &format!("<mi mathvariant=\"{}\">", variant))
+                    .replace("<mn>", &format!("<mn mathvariant=\"{}\">", variant)),
+            ));
+        }
+
+        Ok(Atom::new(match name.as_str() {
+            "frac" | "dfrac" | "tfrac" | "cfrac" => {
+                let numerator = self.parse_argument()?;
+                let denominator = self.parse_argument()?;
+                format!("<mfrac>{}{}</mfrac>", numerator, denominator)
+            }
+            "binom" => {
+                let n = self.parse_argument()?;
+                let k = self.parse_argument()?;
+                format!(
+                    "<mrow><mo>(</mo><mfrac linethickness=\"0\">{}{}</mfrac><mo>)</mo></mrow>",
+                    n, k
+                )
+            }
+            "sqrt" => {
+                if self.peek() == Some(&Token::Symbol('[')) {
+                    self.index += 1;
+                    let mut index = vec![];
+                    while self.peek() != Some(&Token::Symbol(']')) {
+                        if self.at_row_end() {
+                            return Err(self.error("expected `]`"));
+                        }
+                        index.push(self.parse_atom()?);
+                    }
+                    self.index += 1;
+                    let radicand = self.parse_argument()?;
+                    format!("<mroot>{}{}</mroot>", radicand, mrow(index))
+                } else {
+                    format!("<msqrt>{}</msqrt>", self.parse_argument()?)
+                }
+            }
+            "underline" => format!(
+                "<munder accentunder=\"true\">{}<mo stretchy=\"true\">_</mo></munder>",
+                self.parse_argument()?
+            ),
+            "underbrace" => format!(
+                "<munder>{}<mo stretchy=\"true\">⏟</mo></munder>",
+                self.parse_argument()?
+            ),
+            "text" | "textrm" | "textit" | "textbf" | "mbox" => {
+                format!("<mtext>{}</mtext>", escape(&self.parse_raw_group()?))
+            }
+            "operatorname" => {
+                let name = self.parse_raw_group()?;
+                return Ok(Atom::new(format!("<mi>{}</mi>", escape(name.trim()))));
+            }
+            "left" => {
+                let open = self.parse_delimiter()?;
+                let row = self.parse_row()?;
+                match self.peek() {
+                    Some(Token::Command(name)) if name == "right" => self.index += 1,
+                    _ => return Err(self.error("missing `\\right`")),
+                }
+                let close = self.parse_delimiter()?;
+                format!("<mrow>{}{}{}</mrow>", open, row, close)
+            }
+            "big" | "Big" | "bigg" | "Bigg" | "bigl" | "bigr" | "Bigl" | "Bigr" | "biggl"
+            | "biggr" | "Biggl" | "Biggr" | "middle" => self.parse_delimiter()?,
+            "begin" => {
+                let environment = self.parse_raw_group()?;
+                let (open, close) = match environment_delimiters(&environment) {
+                    Some(x) => x,
+                    None => {
+                        return Err(MathError {
+                            message: format!("unknown environment `{}`", environment),
+                            offset: Some(command_offset),
+                        })
+                    }
+                };
+                if environment == "array" {
+                    // Column specification, like `{cc}`
+                    self.parse_raw_group()?;
+                }
+
+                let table = self.parse_rows(Some(&environment))?;
+                self.expect(Token::Command(String::from("end")), "`\\end`")?;
+                let end = self.parse_raw_group()?;
+                if end != environment {
+                    return Err(self.error(format!(
+                        "`\\begin{{{}}}` ended by `\\end{{{}}}`",
+                        environment, end
+                    )));
+                }
+
+                let fence = |x: &str| {
+                    if x.is_empty() {
+                        String::default()
+                    } else {
+                        format!("<mo fence=\"true\" stretchy=\"true\">{}</mo>", escape(x))
+                    }
+                };
+                format!("<mrow>{}{}{}</mrow>", fence(open), table, fence(close))
+            }
+            "displaystyle" | "textstyle" | "scriptstyle" | "nonumber" | "notag" => {
+                String::default()
+            }
+            "label" | "tag" => {
+                self.parse_raw_group()?;
+                String::default()
+            }
+            _ => {
+                return Err(MathError {
+                    message: format!("unknown command `\\{}`", name),
+                    offset: Some(command_offset),
+                })
+            }
+        }))
+    }
+}
@@ -0,0 +1,57 @@
+use rabbit::highlight::*;
+use rabbit::markdown::*;
+use std::path::Path;
+
+#[test]
+fn highlighter_is_shared() {
+    let renderer = Renderer::new(Path::new("."), true);
+    let first = renderer.highlighter() as *const _;
+
+    let html = Parser::new("```rust\nfn a() {}\n```\n\n```rust\nfn b() {}\n```")
+        .get_html(&renderer)
+        .unwrap();
+    assert_eq!(html.matches("<pre").count(), 2);
+    assert_eq!(first, renderer.highlighter() as *const _);
+}
+
+#[test]
+fn highlight_with_classes() {
+    let highlighter = Highlighter::with_theme("InspiredGitHub")
+        .unwrap()
+        .classes(true);
+    assert!(highlighter.stylesheet().contains(".hl-comment {"));
+
+    let renderer = Renderer::new(Path::new("."), true).with_highlighter(highlighter);
+    let html = Parser::new("```rust\n// <note>\n```")
+        .get_html(&renderer)
+        .unwrap();
+    assert!(html.starts_with("<pre class=\"hl-code\">"));
+    assert!(html.contains("<span class=\"hl-comment hl-line hl-double-slash hl-rust\">"));
+    assert!(html.contains("&lt;note&gt;"));
+    assert!(!html.contains("style="));
+}
+
+#[test]
+fn unknown_theme() {
+    match Highlighter::with_theme("no-such-theme") {
+        Err(HighlightError::UnknownTheme(name, available)) => {
+            assert_eq!(name, "no-such-theme");
+            assert!(available.contains(&String::from(DEFAULT_THEME)));
+        }
+        _ => panic!("expected an unknown theme error"),
+    }
+}
+
+#[test]
+fn custom_syntaxes() {
+    let highlighter = Highlighter::new()
+        .add_syntaxes(Path::new("tests/syntaxes"))
+        .unwrap()
+        .classes(true);
+
+    let html = highlighter
+        .highlight("rabbit", "hop to the carrot")
+        .unwrap();
+    assert!(html.contains("<span class=\"hl-keyword hl-control hl-rabbit\">hop</span>"));
+    assert!(highlighter.highlight("rust", "fn main() {}").is_some());
+}
@@ -0,0 +1,189 @@
+use rabbit::markdown::*;
+use rabbit::math::*;
+use std::path::Path;
+
+#[test]
+fn convert_fraction_and_scripts() {
+    assert_eq!(
+        tex_to_mathml("\\frac{a}{b} + x_i^2", false).unwrap(),
+        "<math xmlns=\"http://www.w3.org/1998/Math/MathML\" display=\"inline\">\
+         <mrow><mfrac><mrow><mi>a</mi></mrow><mrow><mi>b</mi></mrow></mfrac>\
+         <mo>+</mo><msubsup><mi>x</mi><mi>i</mi><mn>2</mn></msubsup></mrow></math>"
+    );
+}
+
+#[test]
+fn convert_big_operators() {
+    let html = tex_to_mathml("\\sum_{n=1}^\\infty \\alpha^n", true).unwrap();
+    assert!(html.contains("display=\"block\""));
+    assert!(html.contains(
+        "<munderover><mo largeop=\"true\" movablelimits=\"true\">∑</mo>\
+         <mrow><mi>n</mi><mo>=</mo><mn>1</mn></mrow><mi>∞</mi></munderover>"
+    ));
+    assert!(html.contains("<msup><mi>α</mi><mi>n</mi></msup>"));
+
+    let html = tex_to_mathml("\\int_0^1 x", false).unwrap();
+    assert!(html.contains("<msubsup><mo largeop=\"true\">∫</mo><mn>0</mn><mn>1</mn></msubsup>"));
+}
+
+#[test]
+fn convert_environments() {
+    let html = tex_to_mathml("\\begin{align} a &= b \\\\ c &= d \\end{align}", true).unwrap();
+    assert_eq!(html.matches("<mtr>").count(), 2);
+    assert_eq!(html.matches("<mtd>").count(), 4);
+
+    let html = tex_to_mathml("\\begin{pmatrix} 1 & 0 \\\\ 0 & 1 \\end{pmatrix}", true).unwrap();
+    assert!(html.starts_with(
+        "<math xmlns=\"http://www.w3.org/1998/Math/MathML\" display=\"block\">\
+         <mrow><mo fence=\"true\" stretchy=\"true\">(</mo><mtable>"
+    ));
+}
+
+#[test]
+fn invalid_math() {
+    let error = tex_to_mathml("x + \\nosuchcommand", false).unwrap_err();
+    assert_eq!(error.message, "unknown command `\\nosuchcommand`");
+    assert_eq!(error.offset, Some(4));
+
+    assert!(tex_to_mathml("\\frac{a}{b", false).is_err());
+    assert!(tex_to_mathml("a}", false).is_err());
+    assert!(tex_to_mathml("\\begin{matrix} a \\end{pmatrix}", false).is_err());
+
+    // Deep nesting is an error instead of overflowing the stack
+    let error = tex_to_mathml(&"{".repeat(100_000), false).unwrap_err();
+    assert_eq!(error.message, "math nested too deeply");
+    assert_eq!(error.offset, Some(128));
+    assert!(tex_to_mathml(&"\\sqrt".repeat(100_000), false).is_err());
+    assert!(tex_to_mathml(&"\\left(".repeat(100_000), false).is_err());
+    assert!(tex_to_mathml(&format!("{}x{}", "{".repeat(100), "}".repeat(100)), false).is_ok());
+}
+
+#[test]
+fn render_mathml() {
+    let renderer = Renderer::new(Path::new("."), false);
+    let html = Parser::new("$$\n\\sqrt{2}\n$$\n\nInline $\\pi$ and $\\bad$")
+        .get_html(&renderer)
+        .unwrap();
+
+    assert!(html.contains("<msqrt><mrow><mn>2</mn></mrow></msqrt>"));
+    assert!(html.contains("<mi>π</mi>"));
+    assert!(html.contains("<merror><mtext>\\bad</mtext></merror>"));
+
+    let warnings = renderer.take_warnings();
+    assert_eq!(warnings.len(), 1);
+    assert_eq!((warnings[0].line, warnings[0].column), (5, 18));
+}
+
+#[test]
+fn math_cache() {
+    let cache = MathCache::new();
+    assert_eq!(cache.get("a"), None);
+
+    cache.insert("a", String::from("A")).unwrap();
+    assert_eq!(cache.get("a"), Some(String::from("A")));
+    assert_eq!(cache.clone().get("a"), Some(String::from("A")));
+    assert_eq!(cache.get("b"), None);
+
+    assert_ne!(MathOutput::MathMl.converter(), MathOutput::Svg.converter());
+}
+
+#[test]
+fn math_cache_on_disk() {
+    let dir = std::env::temp_dir().join(format!("rabbit-math-cache-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+
+    let cache = MathCache::with_dir(&dir).unwrap();
+    cache.insert("x^2", String::from("<svg/>")).unwrap();
+
+    let cache = MathCache::with_dir(&dir).unwrap();
+    assert_eq!(cache.get("x^2"), Some(String::from("<svg/>")));
+
+    // A file with the right name but another key is not used
+    let file = std::fs::read_dir(&dir).unwrap().next().unwrap().unwrap();
+    std::fs::write(file.path(), "y^2\0<other/>").unwrap();
+    assert_eq!(MathCache::with_dir(&dir).unwrap().get("x^2"), None);
+
+    // Failing to write is reported but keeps the entry in memory
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert!(cache.insert("z", String::from("<z/>")).is_err());
+    assert_eq!(cache.get("z"), Some(String::from("<z/>")));
+
+    let renderer = Renderer::new(Path::new("."), false).with_math_cache(cache);
+    let html = Parser::new("$a$").get_html(&renderer).unwrap();
+    assert!(html.contains("<mi>a</mi>"));
+    let warnings = renderer.take_warnings();
+    assert_eq!(warnings.len(), 1);
+    assert!(matches!(warnings[0].kind, ParseErrorKind::MathCache(_)));
+}
+
+#[test]
+fn strict_math() {
+    let markdown = "Some text\n\nWith $\\frac{1}{$ math";
+
+    let renderer = Renderer::new(Path::new("."), false);
+    let html = Parser::new(markdown).get_html(&renderer).unwrap();
+    assert!(html.contains("<merror>"));
+    assert_eq!(renderer.take_warnings().len(), 1);
+
+    let renderer = Renderer::new(Path::new("."), false).strict(true);
+    let error = Parser::new(markdown).get_html(&renderer).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "3:6: invalid math: expected `}` at offset 9"
+    );
+}
+
+#[test]
+fn safe_math() {
+    let markdown = "$\\href{javascript:alert(1)}{x}$ and $\\\\href$\n\n\
+                    $$\n\\htmlStyle{color: red}{y}\n$$";
+
+    let renderer = Renderer::new(Path::new("."), true).safe(true);
+    let html = Parser::new(markdown).get_html(&renderer).unwrap();
+    assert_eq!(
+        html,
+        "<p><code>\\href{javascript:alert(1)}{x}</code> and $\\\\href$</p>\
+         <center><code>\\htmlStyle{color: red}{y}</code></center>"
+    );
+    assert_eq!(renderer.take_warnings().len(), 2);
+
+    let renderer = Renderer::new(Path::new("."), true);
+    let html = Parser::new(markdown).get_html(&renderer).unwrap();
+    assert!(html.contains("$\\href{javascript:alert(1)}{x}$"));
+    assert!(renderer.take_warnings().is_empty());
+}
+
+#[test]
+fn equation_references() {
+    let markdown = "$$ a = b $$ {#eq:first}\n\n\
+                    See @eq:second and @eq:first, mail me@eq:first.\n\n\
+                    $$\nE = mc^2\n$$ {#eq:second}\n\n\
+                    @eq:missing\n\n$$ c $$ {#eq:first}";
+
+    let renderer = Renderer::new(Path::new("."), true);
+    let html = Parser::new(markdown).get_html(&renderer).unwrap();
+
+    assert!(html.starts_with(
+        "<center id=\"eq:first\"><span style=\"float: right\">(1)</span>$a = b$</center>"
+    ));
+    assert!(html.contains(
+        "<p>See <a href=\"#eq:second\">Eq. (2)</a> and <a href=\"#eq:first\">Eq. (1)</a>, \
+         mail me@eq:first.</p>"
+    ));
+    assert!(html.contains("<span style=\"float: right\">(2)</span>$E = mc^2$"));
+    assert!(html.contains("<p>@eq:missing</p>"));
+    assert!(html.ends_with("<center><span style=\"float: right\">(3)</span>$c$</center>"));
+    assert_eq!(html.matches("id=\"eq:first\"").count(), 1);
+
+    let warnings = renderer.take_warnings();
+    assert_eq!(warnings.len(), 2);
+    assert_eq!(
+        warnings[0].kind,
+        ParseErrorKind::DuplicateLabel(String::from("eq:first"))
+    );
+    assert_eq!(
+        warnings[1].kind,
+        ParseErrorKind::UnknownReference(String::from("eq:missing"))
+    );
+    assert_eq!(warnings[1].line, 9);
+}
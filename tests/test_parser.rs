@@ -1,18 +1,752 @@
 use rabbit::markdown::*;
+use std::path::Path;
 
 #[test]
 fn empty_parser() {
     let mut parser = Parser::new("");
-    assert!(parser.next_node(false).is_none());
+    assert!(parser.next_node(false).unwrap().is_none());
 }
 
 #[test]
 fn parse_header() {
     let mut parser = Parser::new("# Title");
-    let next_node = parser.next_node(false);
+    let next_node = parser.next_node(false).unwrap();
 
     assert!(next_node.is_some());
     let next_node = next_node.unwrap();
-    assert!(matches!(next_node, MarkdownNode::Header{..}));
+    assert!(matches!(next_node, MarkdownNode::Header { .. }));
 }
 
+#[test]
+fn unclosed_inline_code() {
+    let html = Parser::new("Some `code and $5, $$ or\n\n`x` $y$ next$")
+        .get_html(&Renderer::new(Path::new("."), true))
+        .unwrap();
+    assert_eq!(
+        html,
+        "<p>Some `code and $5, $$ or</p><p><code>x</code> $y$ next$</p>"
+    );
+
+    let mut parser = Parser::new("# Title\n\n```\ncode\n``");
+    assert!(parser.next_node(false).unwrap().is_some());
+
+    let error = parser.next_node(false).unwrap_err();
+    assert_eq!(
+        error.kind,
+        ParseErrorKind::Expected(String::from("```"), String::default())
+    );
+    assert_eq!((error.line, error.column), (5, 3));
+
+    assert!(parser.next().is_none());
+}
+
+#[test]
+fn code_fences() {
+    let mut parser =
+        Parser::new("```sh\necho `date`\n  ```\n\n````md\n```\nnested\n```\n````\nafter");
+
+    let node = parser.next_node(false).unwrap().unwrap();
+    assert!(
+        matches!(node, MarkdownNode::Code(ref lang, ref code, _, _, _)
+        if lang == "sh" && code == "echo `date`")
+    );
+    assert_eq!(node.span().end, 23);
+
+    let node = parser.next_node(false).unwrap().unwrap();
+    assert!(matches!(node, MarkdownNode::Code(_, ref code, _, _, _) if code == "```\nnested\n```"));
+
+    let node = parser.next_node(false).unwrap().unwrap();
+    assert!(matches!(node, MarkdownNode::Paragraph(..)));
+}
+
+#[test]
+fn unknown_code_language() {
+    let renderer = Renderer::new(Path::new("."), true);
+    let mut parser = Parser::new("text\n\n```nosuchlanguage\ncode\n```");
+    let html = parser.get_html(&renderer).unwrap();
+    assert!(html.contains("code"));
+
+    let warnings = renderer.take_warnings();
+    assert_eq!(warnings.len(), 1);
+    assert_eq!(
+        warnings[0].kind,
+        ParseErrorKind::UnknownLanguage(String::from("nosuchlanguage"))
+    );
+    assert_eq!((warnings[0].line, warnings[0].column), (3, 1));
+    assert!(renderer.take_warnings().is_empty());
+}
+
+#[test]
+fn node_spans() {
+    let mut parser = Parser::new("# Title\n\nSome *emphasised* text\n");
+
+    let header = parser.next_node(false).unwrap().unwrap();
+    assert_eq!(
+        header.span(),
+        Span {
+            start: 0,
+            end: 7,
+            line: 1,
+            column: 1
+        }
+    );
+
+    let paragraph = parser.next_node(false).unwrap().unwrap();
+    assert_eq!((paragraph.span().start, paragraph.span().end), (9, 31));
+
+    match paragraph {
+        MarkdownNode::Paragraph(children, _, _) => {
+            let italic = children[1].span();
+            assert_eq!((italic.start, italic.end), (14, 26));
+            assert_eq!((italic.line, italic.column), (3, 6));
+        }
+        _ => panic!("expected a paragraph"),
+    }
+}
+
+#[test]
+fn parse_ordered_list() {
+    let mut parser = Parser::new("5. Five\n6. Six\n1) Other list\n\n2024 was a year");
+
+    let node = parser.next_node(false).unwrap().unwrap();
+    assert!(matches!(node, MarkdownNode::OrderedList(ref items, 5, _) if items.len() == 2));
+    assert_eq!(
+        node.to_html(&Renderer::new(Path::new("."), true)).unwrap(),
+        "<ol start=\"5\"><li>Five</li><li>Six</li></ol>"
+    );
+
+    let node = parser.next_node(false).unwrap().unwrap();
+    assert!(matches!(node, MarkdownNode::OrderedList(ref items, 1, _) if items.len() == 1));
+    assert_eq!(
+        node.to_html(&Renderer::new(Path::new("."), true)).unwrap(),
+        "<ol><li>Other list</li></ol>"
+    );
+
+    let node = parser.next_node(false).unwrap().unwrap();
+    assert!(matches!(node, MarkdownNode::Paragraph(..)));
+}
+
+#[test]
+fn parse_nested_list() {
+    let mut parser = Parser::new("* One\n  1. Sub\n\n     ```\n     More\n* Two\n");
+    let error = parser.next_node(false).unwrap_err();
+    assert_eq!((error.line, error.column), (5, 10));
+
+    let mut parser = Parser::new("* One\n  1. Sub\n\n     Sub text\n* Two\n");
+    let node = parser.next_node(false).unwrap().unwrap();
+    assert_eq!(
+        node.to_html(&Renderer::new(Path::new("."), true)).unwrap(),
+        "<ul><li>One<ol><li>Sub<p>Sub text</p></li></ol></li><li>Two</li></ul>"
+    );
+
+    match node {
+        MarkdownNode::List(items, _) => {
+            assert_eq!(items.len(), 2);
+            assert_eq!((items[0].span.start, items[0].span.end), (0, 29));
+            assert_eq!(items[0].children[1].span().line, 2);
+        }
+        _ => panic!("expected a list"),
+    }
+
+    let markdown = format!("{}x", "- ".repeat(20000));
+    let html = Parser::new(&markdown)
+        .get_html(&Renderer::new(Path::new("."), true))
+        .unwrap();
+    assert_eq!(html.matches("<li>").count(), 64);
+
+    let markdown = format!("{}x", "[^a]: ".repeat(20000));
+    assert!(Parser::new(&markdown)
+        .get_html(&Renderer::new(Path::new("."), true))
+        .is_ok());
+}
+
+#[test]
+fn parse_block_quote() {
+    let mut parser = Parser::new("> # Title\n> 1 < 2\n>\n> > * nested\n\nAfter");
+
+    let node = parser.next_node(false).unwrap().unwrap();
+    assert_eq!(
+        node.to_html(&Renderer::new(Path::new("."), true)).unwrap(),
+        "<blockquote><h1>Title</h1><p>1 &lt; 2</p>\
+         <blockquote><ul><li>nested</li></ul></blockquote></blockquote>"
+    );
+    assert_eq!(node.span().end, 32);
+
+    let node = parser.next_node(false).unwrap().unwrap();
+    assert_eq!(node.span().line, 6);
+
+    // Deeply nested quotes are limited instead of overflowing the stack
+    let markdown = format!("{}x", ">".repeat(20000));
+    let html = Parser::new(&markdown)
+        .get_html(&Renderer::new(Path::new("."), true))
+        .unwrap();
+    assert_eq!(html.matches("<blockquote>").count(), 64);
+    assert!(html.contains("<p>&gt;&gt;"));
+}
+
+#[test]
+fn escape_html_output() {
+    let markdown = "# Fish & <Chips>\n\nA <b>bold</b> \"claim\" with `<code>`\n\n\
+                    [click](javascript:evil) and ![x\"](JavaScript:evil)";
+    let path = Path::new(".");
+
+    let html = Parser::new(markdown)
+        .get_html(&Renderer::new(path, true))
+        .unwrap();
+    assert_eq!(
+        html,
+        "<h1>Fish &amp; &lt;Chips&gt;</h1>\
+         <p>A <b>bold</b> &quot;claim&quot; with <code>&lt;code&gt;</code></p>\
+         <p><a href=\"javascript:evil\">click</a> and \
+         <img src=\"JavaScript:evil\" alt=\"x&quot;\"></p>"
+    );
+
+    let html = Parser::new(markdown)
+        .get_html(&Renderer::new(path, true).safe(true))
+        .unwrap();
+    assert_eq!(
+        html,
+        "<h1>Fish &amp; &lt;Chips&gt;</h1>\
+         <p>A bold &quot;claim&quot; with <code>&lt;code&gt;</code></p>\
+         <p>click and x&quot;</p>"
+    );
+}
+
+#[test]
+fn parse_display_math() {
+    let mut parser = Parser::new(
+        "$x$ is positive\n\n$$\na + b\n  = c\n$$\n\n\\begin{align}\na &= b\n\\end{align}\n\n$$x$$ inline",
+    );
+
+    let node = parser.next_node(false).unwrap().unwrap();
+    assert!(matches!(node, MarkdownNode::Paragraph(ref items, _, _)
+        if matches!(items[0], ParagraphItem::InlineMath(ref x, _) if x == "x")));
+
+    let node = parser.next_node(false).unwrap().unwrap();
+    assert!(matches!(node, MarkdownNode::Math(ref x, None, _) if x == "a + b\n  = c"));
+    assert_eq!((node.span().line, node.span().end), (3, 34));
+
+    let node = parser.next_node(false).unwrap().unwrap();
+    assert!(
+        matches!(node, MarkdownNode::Math(ref x, None, _) if x == "\\begin{align}\na &= b\n\\end{align}")
+    );
+
+    let node = parser.next_node(false).unwrap().unwrap();
+    assert!(matches!(node, MarkdownNode::Paragraph(ref items, _, _)
+        if matches!(items[0], ParagraphItem::InlineMath(ref x, _) if x == "x")));
+
+    let error = Parser::new("$$\nx\n").next_node(false).unwrap_err();
+    assert_eq!(
+        error.kind,
+        ParseErrorKind::Expected(String::from("$$"), String::default())
+    );
+}
+
+#[test]
+fn figures_and_captions() {
+    let first = "![A plot](plot.png) {#fig:plot}\n\n\
+                 See @fig:plot, @tbl:results and @lst:hello.\n\n\
+                 ![](other.png)\n\n\
+                 Inline ![icon](icon.png) image";
+    let second = "| a | b |\n|---|---|\n| 1 | 2 |\n\nTable: Results {#tbl:results}\n\n\
+                  ```\nhello\n```\nListing: Hello {world}";
+
+    let renderer = Renderer::new(Path::new("."), true);
+    let first: Vec<_> = Parser::new(first).map(Result::unwrap).collect();
+    let second: Vec<_> = Parser::new(second).map(Result::unwrap).collect();
+    renderer.add_labels(&first);
+    renderer.add_labels(&second);
+
+    assert!(matches!(first[0], MarkdownNode::Figure(_, Some(ref x), _) if x == "fig:plot"));
+    assert!(matches!(first[3], MarkdownNode::Paragraph(..)));
+    match &second[1] {
+        MarkdownNode::Code(_, code, _, Some(caption), _) => {
+            assert_eq!(code, "hello");
+            assert_eq!(caption.text, "Hello {world}");
+            assert_eq!(caption.label, None);
+            assert_eq!(caption.span.line, 10);
+        }
+        _ => panic!("expected a code block with a caption"),
+    }
+
+    let html = renderer.render(&first).unwrap();
+    assert_eq!(
+        html,
+        "<figure id=\"fig:plot\"><img src=\"plot.png\" alt=\"A plot\">\
+         <figcaption>Figure 1: A plot</figcaption></figure>\
+         <p>See <a href=\"#fig:plot\">Figure 1</a>, <a href=\"#tbl:results\">Table 1</a> \
+         and @lst:hello.</p>\
+         <figure><img src=\"other.png\" alt=\"\"><figcaption>Figure 2</figcaption></figure>\
+         <p>Inline <img src=\"icon.png\" alt=\"icon\"> image</p>"
+    );
+
+    let html = renderer.render(&second).unwrap();
+    assert!(html.starts_with("<table id=\"tbl:results\"><caption>Table 1: Results</caption>"));
+    assert!(html.contains(
+        "<figure class=\"listing\"><figcaption>Listing 1: Hello {world}</figcaption><pre"
+    ));
+
+    let warnings = renderer.take_warnings();
+    assert_eq!(warnings.len(), 1);
+    assert_eq!(
+        warnings[0].kind,
+        ParseErrorKind::UnknownReference(String::from("lst:hello"))
+    );
+}
+
+#[test]
+fn table_alignment_and_pipes() {
+    let mut parser = Parser::new(
+        "| Left | Center | Right | None |\n\
+         | :--- | :----: | ----: | ---- |\n\
+         | a \\| b | `x | y` | `x \\| y` | c |\n\
+         \n\
+         Not | a table",
+    );
+
+    let node = parser.next_node(false).unwrap().unwrap();
+    match &node {
+        MarkdownNode::Table(headers, rows, alignments, None, span) => {
+            assert_eq!(headers.len(), 4);
+            assert_eq!(rows.len(), 1);
+            assert_eq!(
+                alignments,
+                &[
+                    Alignment::Left,
+                    Alignment::Center,
+                    Alignment::Right,
+                    Alignment::None
+                ]
+            );
+            assert_eq!((rows[0][1].span().line, rows[0][1].span().column), (3, 12));
+            assert_eq!(span.line, 1);
+        }
+        _ => panic!("expected a table"),
+    }
+
+    let html = node.to_html(&Renderer::new(Path::new("."), true)).unwrap();
+    assert!(html.contains(
+        "<th style=\"text-align:left\">Left</th><th style=\"text-align:center\">Center</th>\
+         <th style=\"text-align:right\">Right</th><th>None</th>"
+    ));
+    assert!(html.contains(
+        "<td style=\"text-align:left\">a | b</td>\
+         <td style=\"text-align:center\"><code>x | y</code></td>\
+         <td style=\"text-align:right\"><code>x | y</code></td><td>c</td>"
+    ));
+
+    let node = parser.next_node(false).unwrap().unwrap();
+    assert_eq!(
+        node.to_html(&Renderer::new(Path::new("."), true)).unwrap(),
+        "<p>Not | a table</p>"
+    );
+}
+
+#[test]
+fn table_structure() {
+    let mut parser = Parser::new("| a | b |\n|---|---|\n| 1 |\n| 2 | 3 | 4 |\n|   | 5 |\n| x");
+
+    let node = parser.next_node(false).unwrap().unwrap();
+    match &node {
+        MarkdownNode::Table(_, rows, _, _, _) => {
+            assert_eq!(rows.len(), 4);
+            assert!(rows.iter().all(|row| row.len() == 2));
+        }
+        _ => panic!("expected a table"),
+    }
+
+    assert_eq!(
+        node.to_html(&Renderer::new(Path::new("."), true)).unwrap(),
+        "<table><thead><tr><th>a</th><th>b</th></tr></thead><tbody>\
+         <tr><td>1</td><td></td></tr>\
+         <tr><td>2</td><td>3</td></tr>\
+         <tr><td></td><td>5</td></tr>\
+         <tr><td>x</td><td></td></tr>\
+         </tbody></table>"
+    );
+
+    let node = Parser::new("| a |\n| - |")
+        .next_node(false)
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        node.to_html(&Renderer::new(Path::new("."), true)).unwrap(),
+        "<table><thead><tr><th>a</th></tr></thead></table>"
+    );
+}
+
+#[test]
+fn backslash_escapes() {
+    let renderer = Renderer::new(Path::new("."), true);
+    let html = Parser::new(
+        "# Issue \\#12 \\\\\\*\n\n\
+         Prices \\$5 and \\$10, \\*not\\* \\_emphasis\\_, \\[link\\] \\`code\\` \\!\\[x\\]\n\n\
+         \\# Not a header\n\n\
+         \\* Not a list\n\n\
+         1\\. Not a list either\n\n\
+         \\> Not a quote, and a \\ backslash\n\n\
+         \\| Not a table\n\n\
+         \\@eq:x",
+    )
+    .get_html(&renderer)
+    .unwrap();
+
+    assert_eq!(
+        html,
+        "<h1>Issue #12 \\*</h1>\
+         <p>Prices $5 and $10, *not* _emphasis_, [link] `code` ![x]</p>\
+         <p># Not a header</p>\
+         <p>* Not a list</p>\
+         <p>1. Not a list either</p>\
+         <p>&gt; Not a quote, and a \\ backslash</p>\
+         <p>| Not a table</p>\
+         <p>@eq:x</p>"
+    );
+    assert!(renderer.take_warnings().is_empty());
+}
+
+#[test]
+fn nested_emphasis() {
+    let cases = [
+        ("**bold *and italic***", "<b>bold <em>and italic</em></b>"),
+        ("***both***", "<em><b>both</b></em>"),
+        ("*a **b** c*", "<em>a <b>b</b> c</em>"),
+        (
+            "_a [link](url) and `code`_",
+            "<em>a <a href=\"url\">link</a> and <code>code</code></em>",
+        ),
+        ("snake_case_name", "snake_case_name"),
+        ("a * b * c", "a * b * c"),
+        ("*unclosed", "*unclosed"),
+        ("**a*", "*<em>a</em>"),
+        ("*foo**bar**baz*", "<em>foo<b>bar</b>baz</em>"),
+        ("*foo**bar*", "<em>foo**bar</em>"),
+        ("__strong__ and _em_", "<b>strong</b> and <em>em</em>"),
+        ("*(a)*", "<em>(a)</em>"),
+        ("\\*not\\* *yes*", "*not* <em>yes</em>"),
+        ("*a _b* c_", "<em>a _b</em> c_"),
+        ("a* b* *c*", "a* b* <em>c</em>"),
+        ("*a **b** c* d*", "<em>a <b>b</b> c</em> d*"),
+    ];
+
+    let renderer = Renderer::new(Path::new("."), true);
+    for (markdown, html) in cases.iter() {
+        assert_eq!(
+            Parser::new(markdown).get_html(&renderer).unwrap(),
+            format!("<p>{}</p>", html),
+            "{}",
+            markdown
+        );
+    }
+
+    let node = Parser::new("a **b *c***")
+        .next_node(false)
+        .unwrap()
+        .unwrap();
+    match node {
+        MarkdownNode::Paragraph(items, _, _) => match &items[1] {
+            ParagraphItem::Bold(children, span) => {
+                assert_eq!((span.start, span.end), (2, 11));
+                assert!(
+                    matches!(children[1], ParagraphItem::Italic(_, x) if (x.start, x.end) == (6, 9))
+                );
+            }
+            _ => panic!("expected bold text"),
+        },
+        _ => panic!("expected a paragraph"),
+    }
+
+    // Closers without an opener don't search the same delimiters again
+    let markdown = "a* ".repeat(20000);
+    let html = Parser::new(&markdown).get_html(&renderer).unwrap();
+    assert_eq!(html, format!("<p>{}</p>", markdown));
+}
+
+#[test]
+fn extended_inline_formats() {
+    let cases = [
+        ("~~old~~ new", "<del>old</del> new"),
+        ("==important== note", "<mark>important</mark> note"),
+        ("2^10^ and H~2~O", "2<sup>10</sup> and H<sub>2</sub>O"),
+        ("~~**both**~~", "<del><b>both</b></del>"),
+        ("1 + 1 = 2, a ~ b", "1 + 1 = 2, a ~ b"),
+        ("=single=", "=single="),
+        ("\\^not\\^", "^not^"),
+        ("x^2 + y^2 = z^2", "x^2 + y^2 = z^2"),
+        ("O(n^2) vs O(n^3)", "O(n^2) vs O(n^3)"),
+        ("~~a~ b~", "~<sub>a</sub> b~"),
+        ("~~a b~~ and ~a b~", "<del>a b</del> and ~a b~"),
+    ];
+
+    let renderer = Renderer::new(Path::new("."), true);
+    for (markdown, html) in cases.iter() {
+        assert_eq!(
+            Parser::new(markdown).get_html(&renderer).unwrap(),
+            format!("<p>{}</p>", html),
+            "{}",
+            markdown
+        );
+    }
+}
+
+#[test]
+fn task_lists() {
+    let markdown = "# Release\n\n\
+                    - [x] Tag the release\n\
+                    - [ ] Publish\n  \
+                      1. [X] Nested\n\
+                    - [link](url) is not a task\n\
+                    \n\
+                    > * [ ] Quoted";
+    let nodes: Vec<_> = Parser::new(markdown).map(Result::unwrap).collect();
+
+    let tasks = tasks(&nodes);
+    let summary: Vec<_> = tasks.iter().map(|x| (x.checked, x.line)).collect();
+    assert_eq!(summary, [(true, 3), (false, 4), (true, 5), (false, 8)]);
+    assert_eq!(tasks.iter().filter(|x| !x.checked).count(), 2);
+
+    let html = Renderer::new(Path::new("."), true).render(&nodes).unwrap();
+    assert!(html.contains(
+        "<ul><li><input type=\"checkbox\" checked disabled> Tag the release</li>\
+         <li><input type=\"checkbox\" disabled> Publish\
+         <ol><li><input type=\"checkbox\" checked disabled> Nested</li></ol></li>\
+         <li><a href=\"url\">link</a> is not a task</li></ul>"
+    ));
+}
+
+#[test]
+fn footnotes() {
+    let markdown = "Second[^b], first[^a] and again[^b].\n\n\
+                    [^a]: Note a.\n\
+                    [^b]: Note b\n    \
+                    continues with[^a] *here*.\n\n\
+                    Missing[^c].";
+    let renderer = Renderer::new(Path::new("."), true);
+    let html = Parser::new(markdown).get_html(&renderer).unwrap();
+
+    assert!(html.starts_with(
+        "<p>Second<sup class=\"footnote-ref\" id=\"fnref:1\"><a href=\"#fn:1\">1</a></sup>, \
+         first<sup class=\"footnote-ref\" id=\"fnref:2\"><a href=\"#fn:2\">2</a></sup> \
+         and again<sup class=\"footnote-ref\" id=\"fnref:1:2\"><a href=\"#fn:1\">1</a></sup>.</p>"
+    ));
+    assert!(html.contains("<p>Missing[^c].</p>"));
+    assert!(html.ends_with(
+        "<section class=\"footnotes\"><hr><ol>\
+         <li id=\"fn:1\"><p>Note b</p><p>continues with\
+         <sup class=\"footnote-ref\" id=\"fnref:2:2\"><a href=\"#fn:2\">2</a></sup> <em>here</em>. \
+         <a href=\"#fnref:1\" class=\"footnote-backref\">↩</a> \
+         <a href=\"#fnref:1:2\" class=\"footnote-backref\">↩</a></p></li>\
+         <li id=\"fn:2\"><p>Note a. <a href=\"#fnref:2\" class=\"footnote-backref\">↩</a> \
+         <a href=\"#fnref:2:2\" class=\"footnote-backref\">↩</a></p></li></ol></section>"
+    ));
+
+    let warnings = renderer.take_warnings();
+    assert_eq!(warnings.len(), 1);
+    assert_eq!(
+        warnings[0].kind,
+        ParseErrorKind::UnknownFootnote(String::from("c"))
+    );
+    assert_eq!((warnings[0].line, warnings[0].column), (7, 8));
+
+    // Every document numbers its own footnotes
+    let mut parser = Parser::new("Other[^a]\n\n[^a]: Other note");
+    let nodes: Vec<_> = parser.by_ref().map(Result::unwrap).collect();
+    let html = renderer
+        .render_document(&nodes, &parser.take_footnotes())
+        .unwrap();
+    assert!(html.contains("id=\"fn2ref:1\"><a href=\"#fn2:1\">1</a>"));
+    assert!(html.contains("<li id=\"fn2:1\"><p>Other note"));
+}
+
+#[test]
+fn reference_links() {
+    let markdown = "See [the docs][Docs], [docs][] and [Docs] or [missing] and [a][b.\n\n\
+                    ![Logo][logo]\n\n\
+                    [docs]: https://example.com/docs  \"The docs\"\n\
+                    > [LOGO]: <logo.png>\n\n\
+                    ```\n[code]: not-a-link\n```\n\n\
+                    [code] [x]: y z";
+    let nodes: Vec<_> = Parser::new(markdown).map(Result::unwrap).collect();
+    assert_eq!(nodes.len(), 5);
+    assert!(
+        matches!(nodes[1], MarkdownNode::Figure(ParagraphItem::Image(ref url, _, _, _), _, _)
+        if url == "logo.png")
+    );
+
+    let html = Renderer::new(Path::new("."), true).render(&nodes).unwrap();
+    assert!(html.starts_with(
+        "<p>See <a href=\"https://example.com/docs\" title=\"The docs\">the docs</a>, \
+         <a href=\"https://example.com/docs\" title=\"The docs\">docs</a> and \
+         <a href=\"https://example.com/docs\" title=\"The docs\">Docs</a> \
+         or [missing] and [a][b.</p>"
+    ));
+    assert!(html.contains("<blockquote></blockquote>"));
+    assert!(html.ends_with("<p>[code] [x]: y z</p>"));
+}
+
+#[test]
+fn titles_and_attributes() {
+    let markdown = "# Introduction {#intro .big}\n\n\
+                    [Docs](https://example.com \"The docs\"){.button onclick=evil()} and \
+                    [file](my file.md) and ![x](x.png 'An x'){width=50%} {not attributes}\n\n\
+                    ![Plot](plot.png){#fig:plot .wide}\n\n\
+                    ```rust {#main .numberLines data-line=\"1 3\"}\nfn main() {}\n```\n\n\
+                    [ref]\n\n\
+                    [ref]: /ref (Titled)";
+    let nodes: Vec<_> = Parser::new(markdown).map(Result::unwrap).collect();
+
+    match &nodes[0] {
+        MarkdownNode::Header(text, 1, attributes, _) => {
+            assert_eq!(text, "Introduction");
+            assert_eq!(attributes.id.as_deref(), Some("intro"));
+            assert_eq!(attributes.classes, ["big"]);
+        }
+        _ => panic!("expected a header"),
+    }
+    assert!(matches!(nodes[2], MarkdownNode::Figure(_, Some(ref x), _) if x == "fig:plot"));
+    assert!(
+        matches!(nodes[3], MarkdownNode::Code(ref lang, _, ref attributes, _, _)
+        if lang == "rust" && attributes.values == [(String::from("data-line"), String::from("1 3"))])
+    );
+
+    let html = Renderer::new(Path::new("."), true).render(&nodes).unwrap();
+    assert!(html.starts_with("<h1 id=\"intro\" class=\"big\">Introduction</h1>"));
+    assert!(html.contains(
+        "<p><a href=\"https://example.com\" class=\"button\" title=\"The docs\" onclick=\"evil()\">\
+         Docs</a> and <a href=\"my file.md\">file</a> and \
+         <img src=\"x.png\" alt=\"x\" title=\"An x\" width=\"50%\"> {not attributes}</p>"
+    ));
+    assert!(
+        html.contains("<figure id=\"fig:plot\"><img src=\"plot.png\" alt=\"Plot\" class=\"wide\">")
+    );
+    assert!(html.contains("<pre id=\"main\" class=\"numberLines\" data-line=\"1 3\" style="));
+    assert!(html.ends_with("<p><a href=\"/ref\" title=\"Titled\">ref</a></p>"));
+
+    let html = Renderer::new(Path::new("."), true)
+        .safe(true)
+        .render(&nodes)
+        .unwrap();
+    assert!(html.contains("class=\"button\" title=\"The docs\">Docs</a>"));
+
+    let markdown = "# Header {style=position:fixed data-x=1}\n\n\
+                    [x](y \"t\"){title=u srcset=evil.png width=10}";
+    let other: Vec<_> = Parser::new(markdown).map(Result::unwrap).collect();
+    let html = Renderer::new(Path::new("."), true).render(&other).unwrap();
+    assert!(html.contains("<a href=\"y\" title=\"u\" srcset=\"evil.png\" width=\"10\">x</a>"));
+    let html = Renderer::new(Path::new("."), true)
+        .safe(true)
+        .render(&other)
+        .unwrap();
+    assert_eq!(
+        html,
+        "<h1 data-x=\"1\">Header</h1><p><a href=\"y\" title=\"u\" width=\"10\">x</a></p>"
+    );
+
+    let renderer = Renderer::new(Path::new("."), true)
+        .with_highlighter(rabbit::highlight::Highlighter::default().classes(true));
+    let html = renderer.render(&nodes[3..4]).unwrap();
+    assert!(html.starts_with("<pre id=\"main\" data-line=\"1 3\" class=\"numberLines hl-code\">"));
+}
+
+#[test]
+fn formatted_link_text() {
+    let markdown = "[**bold** link](x) [`a]`](y) [![badge](badge.svg)](https://ci) \
+                    [a [b] c](z) *[not* closed](w) <https://auto>";
+    let mut parser = Parser::new(markdown);
+    let node = parser.next_node(false).unwrap().unwrap();
+
+    match &node {
+        MarkdownNode::Paragraph(items, _, _) => match &items[0] {
+            ParagraphItem::Url(children, url, _, span) => {
+                assert_eq!(url, "x");
+                assert!(matches!(children[0], ParagraphItem::Bold(..)));
+                assert_eq!((children[0].span().start, children[0].span().end), (1, 9));
+                assert_eq!((span.start, span.end), (0, 18));
+            }
+            _ => panic!("expected a link"),
+        },
+        _ => panic!("expected a paragraph"),
+    }
+
+    let html = node.to_html(&Renderer::new(Path::new("."), true)).unwrap();
+    assert_eq!(
+        html,
+        "<p><a href=\"x\"><b>bold</b> link</a> \
+         <a href=\"y\"><code>a]</code></a> \
+         <a href=\"https://ci\"><img src=\"badge.svg\" alt=\"badge\"></a> \
+         <a href=\"z\">a [b] c</a> \
+         *<a href=\"w\">not* closed</a> \
+         <a href=\"https://auto\">https://auto</a></p>"
+    );
+
+    let html = Parser::new("[a\\\nb](c) tail\n\n[a [b](c) *d*](e) [![i](i.png) [x](y)](z)")
+        .get_html(&Renderer::new(Path::new("."), true))
+        .unwrap();
+    assert_eq!(
+        html,
+        "<p>[a\\</p><p>b](c) tail</p>\
+         <p>[a <a href=\"c\">b</a> <em>d</em>](e) \
+         [<img src=\"i.png\" alt=\"i\"> <a href=\"y\">x</a>](z)</p>"
+    );
+
+    // Parentheses in a url are balanced, an unclosed destination is text
+    let html =
+        Parser::new("[a](http://x.com/a_(b)) [e](f \"g (h)\")\n\n[a](b\n\n![a](b\n\n[c](d \"e\"")
+            .get_html(&Renderer::new(Path::new("."), true))
+            .unwrap();
+    assert_eq!(
+        html,
+        "<p><a href=\"http://x.com/a_(b)\">a</a> \
+         <a href=\"f\" title=\"g (h)\">e</a></p>\
+         <p>[a](b</p><p>![a](b</p><p>[c](d &quot;e&quot;</p>"
+    );
+
+    // Deeply nested brackets are limited instead of overflowing the stack
+    let markdown = format!("{}x{}", "[".repeat(2000), "](a)".repeat(2000));
+    let html = Parser::new(&markdown)
+        .get_html(&Renderer::new(Path::new("."), true))
+        .unwrap();
+    assert_eq!(html.matches("<a href").count(), 1);
+
+    let markdown = format!("{}x](a)", "[ `[` ".repeat(20000));
+    let html = Parser::new(&markdown)
+        .get_html(&Renderer::new(Path::new("."), true))
+        .unwrap();
+    assert!(html.ends_with("<a href=\"a\"> <code>[</code> x</a></p>"));
+
+    let html = Parser::new("[*safe*](javascript:evil)")
+        .get_html(&Renderer::new(Path::new("."), true).safe(true))
+        .unwrap();
+    assert_eq!(html, "<p><em>safe</em></p>");
+}
+
+#[test]
+fn footnote_labels() {
+    let first = "See @fig:note and @fig:last.[^1]\n\n\
+                 [^1]: Details[^2]\n\n\
+                 [^2]: ![Plot](plot.png) {#fig:note}\n\n\
+                 [^unused]: ![Other](other.png)";
+    let second = "![Last](last.png) {#fig:last}";
+
+    let renderer = Renderer::new(Path::new("."), true);
+    let mut documents = vec![];
+    for markdown in &[first, second] {
+        let mut parser = Parser::new(markdown);
+        let nodes: Vec<_> = parser.by_ref().map(Result::unwrap).collect();
+        let footnotes = parser.take_footnotes();
+        renderer.add_document_labels(&nodes, &footnotes);
+        documents.push((nodes, footnotes));
+    }
+
+    let html: Vec<_> = documents
+        .iter()
+        .map(|(nodes, footnotes)| renderer.render_document(nodes, footnotes).unwrap())
+        .collect();
+    assert!(html[0].starts_with(
+        "<p>See <a href=\"#fig:note\">Figure 1</a> and <a href=\"#fig:last\">Figure 2</a>."
+    ));
+    assert!(html[0].contains("<figcaption>Figure 1: Plot</figcaption>"));
+    assert!(!html[0].contains("Other"));
+    assert!(html[1].contains("<figcaption>Figure 2: Last</figcaption>"));
+    assert!(renderer.take_warnings().is_empty());
+}